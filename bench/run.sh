@@ -0,0 +1,11 @@
+#!/bin/sh
+# Runs every benchmark with the given interpreter (default: the release build)
+# and prints the elapsed time each one reports on its last line.
+#
+#   bench/run.sh [path/to/clox-rs] > bench_output.txt
+set -e
+cd "$(dirname "$0")"
+lox="${1:-../target/release/clox-rs}"
+for script in *.lox; do
+    printf '%-14s %s\n' "$script" "$("$lox" "$script" | tail -n 1)"
+done
@@ -1,35 +1,418 @@
-use crate::value::Value;
-#[derive(Debug, Clone)]
+use std::collections::HashMap;
+
+use crate::memory::GcRef;
+use crate::object::{Class, Closure, LoxString};
+use crate::value::{Unpacked, Value};
+// Instructions are one opcode byte followed by its operands, if any:
+// constant indexes, local slots, upvalue indexes and argument counts take one
+// byte; jump offsets take two (big-endian). Property accesses also name their
+// inline cache, in two bytes. OP_INVOKE takes a name constant, an argument
+// count and a cache index.
+//
+// Every opcode whose first operand is a constant index has a long form
+// (OP_CONSTANT_LONG, OP_GET_GLOBAL_LONG, ...) where that index takes three
+// bytes, for constants past the first 256; its other operands are unchanged.
+//
+// The opcodes after SuperInvokeLong are superinstructions: the optimizer fuses
+// common sequences into them so the VM dispatches once instead of several times.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OpCode {
     Negate,
     Return,
-    Constant(usize),
+    Constant,     // Constant index
+    ConstantLong, // Three-byte constant index, for constants past the first 256
     Add,
     Subtract,
     Multiply,
     Divide,
+    Nil,
+    True,
+    False,
+    Not,
+    Equal,
+    Greater,
+    Less,
+    Print,
+    Pop,
+    DefineGlobal,     // Name constant index
+    GetGlobal,        // Name constant index
+    SetGlobal,        // Name constant index
+    GetLocal,         // Stack slot
+    SetLocal,         // Stack slot
+    Jump,             // Unconditional forward jump by the given offset
+    JumpIfFalse,      // Forward jump if the top of the stack is falsey (does not pop)
+    Loop,             // Unconditional backward jump by the given offset
+    Call,             // Call the value below the given number of arguments
+    Closure,          // Wrap the function constant in a closure, capturing its upvalues
+    GetUpvalue,       // Upvalue index
+    SetUpvalue,       // Upvalue index
+    CloseUpvalue,     // Move the local on top of the stack into its upvalue, then pop it
+    Class,            // Create a class named by the given constant
+    GetProperty,      // Name constant index, inline cache index
+    SetProperty,      // Name constant index, inline cache index
+    Method,           // Add the closure on top of the stack as a method of the class below it
+    Inherit,          // Copy the superclass's methods down into the subclass on top of the stack
+    GetSuper,         // Name constant index
+    Invoke,           // Method name constant, argument count, inline cache index
+    SuperInvoke,      // Method name constant, argument count
+    DefineGlobalLong, // The long forms, see above
+    GetGlobalLong,
+    SetGlobalLong,
+    ClosureLong,
+    ClassLong,
+    GetPropertyLong,
+    SetPropertyLong,
+    MethodLong,
+    GetSuperLong,
+    InvokeLong,
+    SuperInvokeLong,
+    PopN,                     // Pop the given number of values
+    AddConstant,              // Constant + Add: add the given constant to the top of the stack
+    SubtractConstant,         // Constant + Subtract
+    GetLocalAddConstant,      // GetLocal + Constant + Add: stack slot, constant index
+    GetLocalSubtractConstant, // GetLocal + Constant + Subtract
+    JumpIfNotLess, // Less + JumpIfFalse + Pop: pop both operands, jump forward unless a < b
+    JumpIfNotGreater, // Greater + JumpIfFalse + Pop
+    JumpIfNotEqual, // Equal + JumpIfFalse + Pop
 }
+
+impl OpCode {
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        Some(match byte {
+            0 => OpCode::Negate,
+            1 => OpCode::Return,
+            2 => OpCode::Constant,
+            3 => OpCode::ConstantLong,
+            4 => OpCode::Add,
+            5 => OpCode::Subtract,
+            6 => OpCode::Multiply,
+            7 => OpCode::Divide,
+            8 => OpCode::Nil,
+            9 => OpCode::True,
+            10 => OpCode::False,
+            11 => OpCode::Not,
+            12 => OpCode::Equal,
+            13 => OpCode::Greater,
+            14 => OpCode::Less,
+            15 => OpCode::Print,
+            16 => OpCode::Pop,
+            17 => OpCode::DefineGlobal,
+            18 => OpCode::GetGlobal,
+            19 => OpCode::SetGlobal,
+            20 => OpCode::GetLocal,
+            21 => OpCode::SetLocal,
+            22 => OpCode::Jump,
+            23 => OpCode::JumpIfFalse,
+            24 => OpCode::Loop,
+            25 => OpCode::Call,
+            26 => OpCode::Closure,
+            27 => OpCode::GetUpvalue,
+            28 => OpCode::SetUpvalue,
+            29 => OpCode::CloseUpvalue,
+            30 => OpCode::Class,
+            31 => OpCode::GetProperty,
+            32 => OpCode::SetProperty,
+            33 => OpCode::Method,
+            34 => OpCode::Inherit,
+            35 => OpCode::GetSuper,
+            36 => OpCode::Invoke,
+            37 => OpCode::SuperInvoke,
+            38 => OpCode::DefineGlobalLong,
+            39 => OpCode::GetGlobalLong,
+            40 => OpCode::SetGlobalLong,
+            41 => OpCode::ClosureLong,
+            42 => OpCode::ClassLong,
+            43 => OpCode::GetPropertyLong,
+            44 => OpCode::SetPropertyLong,
+            45 => OpCode::MethodLong,
+            46 => OpCode::GetSuperLong,
+            47 => OpCode::InvokeLong,
+            48 => OpCode::SuperInvokeLong,
+            49 => OpCode::PopN,
+            50 => OpCode::AddConstant,
+            51 => OpCode::SubtractConstant,
+            52 => OpCode::GetLocalAddConstant,
+            53 => OpCode::GetLocalSubtractConstant,
+            54 => OpCode::JumpIfNotLess,
+            55 => OpCode::JumpIfNotGreater,
+            56 => OpCode::JumpIfNotEqual,
+            _ => return None,
+        })
+    }
+
+    // Number of bytes the instruction takes, opcode included.
+    pub fn size(self) -> usize {
+        match self {
+            OpCode::Constant
+            | OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::Call
+            | OpCode::Closure
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Class
+            | OpCode::Method
+            | OpCode::GetSuper
+            | OpCode::PopN
+            | OpCode::AddConstant
+            | OpCode::SubtractConstant => 2,
+            OpCode::SuperInvoke
+            | OpCode::GetLocalAddConstant
+            | OpCode::GetLocalSubtractConstant => 3,
+            op if op.is_jump() => 3,
+            OpCode::GetProperty | OpCode::SetProperty => 4,
+            OpCode::Invoke => 5,
+            // Two more index bytes than the short form.
+            op if op.is_long() => op.short_form().size() + 2,
+            _ => 1,
+        }
+    }
+
+    // The long form of an opcode whose first operand is a constant index.
+    pub fn long_form(self) -> Option<OpCode> {
+        Some(match self {
+            OpCode::Constant => OpCode::ConstantLong,
+            OpCode::DefineGlobal => OpCode::DefineGlobalLong,
+            OpCode::GetGlobal => OpCode::GetGlobalLong,
+            OpCode::SetGlobal => OpCode::SetGlobalLong,
+            OpCode::Closure => OpCode::ClosureLong,
+            OpCode::Class => OpCode::ClassLong,
+            OpCode::GetProperty => OpCode::GetPropertyLong,
+            OpCode::SetProperty => OpCode::SetPropertyLong,
+            OpCode::Method => OpCode::MethodLong,
+            OpCode::GetSuper => OpCode::GetSuperLong,
+            OpCode::Invoke => OpCode::InvokeLong,
+            OpCode::SuperInvoke => OpCode::SuperInvokeLong,
+            _ => return None,
+        })
+    }
+
+    // The opcode a long form widens; any other opcode is returned unchanged.
+    pub fn short_form(self) -> OpCode {
+        match self {
+            OpCode::ConstantLong => OpCode::Constant,
+            OpCode::DefineGlobalLong => OpCode::DefineGlobal,
+            OpCode::GetGlobalLong => OpCode::GetGlobal,
+            OpCode::SetGlobalLong => OpCode::SetGlobal,
+            OpCode::ClosureLong => OpCode::Closure,
+            OpCode::ClassLong => OpCode::Class,
+            OpCode::GetPropertyLong => OpCode::GetProperty,
+            OpCode::SetPropertyLong => OpCode::SetProperty,
+            OpCode::MethodLong => OpCode::Method,
+            OpCode::GetSuperLong => OpCode::GetSuper,
+            OpCode::InvokeLong => OpCode::Invoke,
+            OpCode::SuperInvokeLong => OpCode::SuperInvoke,
+            op => op,
+        }
+    }
+
+    pub fn is_long(self) -> bool {
+        self.short_form() != self
+    }
+
+    // Whether the instruction's operand is a jump offset. OP_LOOP jumps backward,
+    // all others forward.
+    pub fn is_jump(self) -> bool {
+        matches!(
+            self,
+            OpCode::Jump
+                | OpCode::JumpIfFalse
+                | OpCode::Loop
+                | OpCode::JumpIfNotLess
+                | OpCode::JumpIfNotGreater
+                | OpCode::JumpIfNotEqual
+        )
+    }
+}
+
+// The first instruction byte of a run of code compiled from the same line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineStart {
+    pub offset: usize,
+    pub line: usize,
+}
+
+// A site that has met more classes than this stops caching.
+pub const CACHE_CLASSES: usize = 4;
+
+// What an OP_GET_PROPERTY or OP_SET_PROPERTY site found for each class it has
+// met, so the next access to an instance of one of them can skip the hash lookup.
+// Every hit is checked (see `VM::get_property`), so a stale entry only costs a miss.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InlineCache {
+    entries: [CacheEntry; CACHE_CLASSES], // Filled in the order the classes turn up
+    megamorphic: bool,
+}
+
+impl InlineCache {
+    // The entry for instances of `class`: Megamorphic if the site gave up,
+    // Empty if it hasn't met the class yet.
+    pub fn entry(&self, class: GcRef<Class>) -> CacheEntry {
+        if self.megamorphic {
+            return CacheEntry::Megamorphic;
+        }
+        self.entries
+            .iter()
+            .find(|entry| entry.class() == Some(class))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // Stores what a slow lookup found, replacing the entry for the same class.
+    // A new class takes a free entry; when there are none left the site is
+    // megamorphic and stops caching for good.
+    pub fn update(&mut self, entry: CacheEntry) {
+        let class = entry.class();
+        let free = self
+            .entries
+            .iter()
+            .position(|cached| cached.class() == class)
+            .or_else(|| {
+                self.entries
+                    .iter()
+                    .position(|cached| cached.class().is_none())
+            });
+        match free {
+            Some(index) => self.entries[index] = entry,
+            None => {
+                self.entries = Default::default();
+                self.megamorphic = true;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub enum CacheEntry {
+    #[default]
+    Empty,
+    // The field was in bucket `slot` of the instance's field table.
+    Field {
+        class: GcRef<Class>,
+        slot: usize,
+    },
+    // There was no field, and the class's method was `method`. Only valid while
+    // `version` matches the class's, which changes whenever its method table does.
+    // Versions are never reused, so a freed class's handle given to a new class
+    // doesn't match either.
+    Method {
+        class: GcRef<Class>,
+        method: GcRef<Closure>,
+        version: u64,
+    },
+    // The site has met too many classes to be worth caching.
+    Megamorphic,
+}
+
+impl CacheEntry {
+    // The class this entry was filled for, if any.
+    pub fn class(&self) -> Option<GcRef<Class>> {
+        match *self {
+            CacheEntry::Field { class, .. } | CacheEntry::Method { class, .. } => Some(class),
+            CacheEntry::Empty | CacheEntry::Megamorphic => None,
+        }
+    }
+}
+
+// What `Chunk::add_constant` looks a constant up by: a number's bits (so 0
+// and -0 stay distinct constants) or an interned string's handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConstantKey {
+    Number(u64),
+    String(GcRef<LoxString>),
+}
+
+impl ConstantKey {
+    // None for values that are never shared, such as functions.
+    fn of(value: Value) -> Option<ConstantKey> {
+        match value.unpack() {
+            Unpacked::Number(n) => Some(ConstantKey::Number(n.to_bits())),
+            Unpacked::String(s) => Some(ConstantKey::String(s)),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct Chunk {
-    pub code: Vec<OpCode>,
+    pub code: Vec<u8>,
     pub constants: Vec<Value>,
-    pub line_numbers: Vec<usize>, // Optional: to track line numbers for debugging
+    pub lines: Vec<LineStart>, // Run-length encoded line numbers, by increasing offset
+    pub caches: Vec<InlineCache>, // One per property access, indexed by its cache operand
+    // Where each number and string constant is. Only `add_constant` keeps it up
+    // to date, so constants pushed directly can't be shared afterwards.
+    pub constant_indexes: HashMap<ConstantKey, usize>,
 }
 impl Chunk {
     pub fn new() -> Self {
         Chunk {
             code: Vec::new(),
             constants: Vec::new(),
-            line_numbers: Vec::new(), // Initialize with an empty vector
+            lines: Vec::new(),
+            caches: Vec::new(),
+            constant_indexes: HashMap::new(),
         }
     }
+    // Returns the index of `constant`, reusing an existing slot for a number or
+    // (interned) string that is already in the table.
     pub fn add_constant(&mut self, constant: Value) -> usize {
+        let key = ConstantKey::of(constant);
+        if let Some(&index) = key.and_then(|key| self.constant_indexes.get(&key)) {
+            return index;
+        }
         self.constants.push(constant);
-        self.constants.len() - 1
+        let index = self.constants.len() - 1;
+        if let Some(key) = key {
+            self.constant_indexes.insert(key, index);
+        }
+        index
+    }
+    pub fn write(&mut self, byte: u8, line: usize) {
+        // A new run starts only when the line changes.
+        if self.lines.last().is_none_or(|run| run.line != line) {
+            self.lines.push(LineStart {
+                offset: self.code.len(),
+                line,
+            });
+        }
+        self.code.push(byte);
+    }
+    // The source line of the byte at `offset`.
+    pub fn line(&self, offset: usize) -> usize {
+        let run = self.lines.partition_point(|run| run.offset <= offset);
+        self.lines[run - 1].line
+    }
+    // The offset of each instruction, in order. Unknown opcodes count as one byte.
+    pub fn offsets(&self) -> impl Iterator<Item = usize> + '_ {
+        let first = (!self.code.is_empty()).then_some(0);
+        std::iter::successors(first, |&offset| {
+            let next = offset + self.op(offset).map_or(1, OpCode::size);
+            (next < self.code.len()).then_some(next)
+        })
+    }
+    // The opcode at `offset`, which must be the start of an instruction.
+    pub fn op(&self, offset: usize) -> Option<OpCode> {
+        OpCode::from_byte(self.code[offset])
+    }
+    // The offset the jump instruction at `offset` transfers control to.
+    // Jump operands are relative to the end of the jump instruction.
+    pub fn jump_target(&self, offset: usize) -> usize {
+        let jump = self.read_u16(offset + 1);
+        match self.op(offset) {
+            Some(OpCode::Loop) => offset + 3 - jump,
+            _ => offset + 3 + jump,
+        }
+    }
+    pub fn read_u16(&self, offset: usize) -> usize {
+        (self.code[offset] as usize) << 8 | self.code[offset + 1] as usize
     }
-    pub fn write_chunk(&mut self, op: OpCode, current_line_number: usize) {
-        self.code.push(op);
-        // Optionally, you can also track the line number for each operation
-        self.line_numbers.push(current_line_number); // You would need to define how to get the current line number
+    pub fn read_u24(&self, offset: usize) -> usize {
+        (self.code[offset] as usize) << 16
+            | (self.code[offset + 1] as usize) << 8
+            | self.code[offset + 2] as usize
     }
 }
@@ -1,8 +1,16 @@
+use std::mem;
+
 use crate::{
-    chunk::{Chunk, OpCode},
-    scanner::{Scanner, Token},
+    chunk::{Chunk, InlineCache, OpCode},
+    diagnostic::{Diagnostic, Severity},
+    memory::{GcRef, HeapObject},
+    object::{Function, LoxString, UpvalueRef},
+    optimize,
+    scanner::{Scanner, Span, Token},
+    table::hash_string,
     token_type::{self, TokenType},
     value::Value,
+    vm::VM,
 };
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Precedence {
@@ -20,22 +28,104 @@ pub enum Precedence {
 }
 // Type alias for parsing functions. They take a mutable reference to the Parser
 // and perform parsing actions, reporting errors via parser.had_error.
-pub type ParseFn = fn(&mut Parser); // <--- Changed signature
+// The bool is `can_assign`: whether an `=` following the expression may be consumed.
+pub type ParseFn = fn(&mut Parser, bool);
 #[derive(Copy, Clone)] // Need Copy/Clone for static array initialization
 pub struct ParseRule {
     pub prefix: Option<ParseFn>,
     pub infix: Option<ParseFn>,
     pub precedence: Precedence,
 }
-pub struct Parser {
+// Maximum number of locals in scope at once; slot operands must fit in one byte.
+const MAX_LOCALS: usize = 256;
+
+// Maximum number of arguments in a call (and parameters in a declaration).
+const MAX_ARGS: usize = 255;
+
+// A local variable known at compile time. Its index in `Compiler.locals`
+// is the stack slot it will occupy at runtime, relative to the call frame.
+struct Local {
+    name: Token,
+    depth: Option<usize>, // None while the variable's initializer is being compiled
+    is_captured: bool, // Captured by a closure, so it must be closed over when it goes out of scope
+}
+
+// Maximum number of upvalues a single function can capture.
+const MAX_UPVALUES: usize = 256;
+
+// Highest constant index OP_CONSTANT_LONG's three-byte operand can hold.
+const MAX_CONSTANTS: usize = (1 << 24) - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionType {
+    Function,
+    Initializer, // A class's `init` method, which always returns `this`
+    Method,
+    Script, // The implicit function wrapping top-level code
+}
+
+// Per-function compilation state. A new one is pushed onto `Parser.compilers`
+// for every function declaration and popped once its body is compiled.
+struct Compiler {
+    function: Function, // The function whose chunk is being written
+    kind: FunctionType,
+    locals: Vec<Local>, // Locals currently in scope, innermost last
+    scope_depth: usize, // 0 is global scope, each nested block adds one
+}
+
+impl Compiler {
+    fn new(kind: FunctionType, name: Option<GcRef<LoxString>>) -> Self {
+        Compiler {
+            function: Function::new(name),
+            kind,
+            // Slot zero holds the function being called, so it can't be named by user code.
+            // In methods it holds the receiver instead, which is reachable as `this`.
+            locals: vec![Local {
+                name: Token {
+                    kind: TokenType::Identifier,
+                    lexeme: match kind {
+                        FunctionType::Method | FunctionType::Initializer => "this".to_string(),
+                        FunctionType::Function | FunctionType::Script => "".to_string(),
+                    },
+                    line: 0,
+                    column: 0,
+                    span: Span::default(),
+                },
+                depth: Some(0),
+                is_captured: false,
+            }],
+            scope_depth: 0,
+        }
+    }
+}
+
+// Per-class compilation state, tracked so `this` and `super` can be rejected
+// where they have no meaning.
+struct ClassCompiler {
+    has_superclass: bool,
+}
+
+// A token that doesn't appear in the source, used to name hidden locals.
+fn synthetic_token(text: &str) -> Token {
+    Token {
+        kind: TokenType::Identifier,
+        lexeme: text.to_string(),
+        line: 0,
+        column: 0,
+        span: Span::default(),
+    }
+}
+
+pub struct Parser<'a> {
     current: Token,
     previous: Token,
-    compiling_chunk: Chunk, // Parser owns the chunk it's compiling into
-    scanner: Scanner,       // Parser owns its scanner
+    scanner: Scanner, // Parser owns its scanner
     had_error: bool,
-    // Implemention note: If you were compiling multiple functions/methods,
-    // you might need a stack of Compiler structs or similar state here,
-    // but for a simple top-level script, owning the chunk is fine.
+    diagnostics: Vec<Diagnostic>, // Everything reported so far, in source order
+    panic_mode: bool, // Set while recovering from an error, suppresses cascading reports
+    compilers: Vec<Compiler>, // Enclosing function compilers, innermost last
+    classes: Vec<ClassCompiler>, // Enclosing class declarations, innermost last
+    vm: &'a mut VM,   // Owns the heap that strings and functions are allocated in
 }
 // ... (previous imports, structs)
 
@@ -43,39 +133,49 @@ pub struct Parser {
 // These functions have the signature expected by ParseFn and call
 // the actual parsing logic methods on the Parser instance.
 
-fn number_rule(parser: &mut Parser) {
+fn number_rule(parser: &mut Parser, _can_assign: bool) {
     parser.number();
 }
-fn grouping_rule(parser: &mut Parser) {
+fn grouping_rule(parser: &mut Parser, _can_assign: bool) {
     parser.grouping();
 }
-fn unary_rule(parser: &mut Parser) {
+fn unary_rule(parser: &mut Parser, _can_assign: bool) {
     parser.unary();
 }
-fn binary_rule(parser: &mut Parser) {
+fn binary_rule(parser: &mut Parser, _can_assign: bool) {
     parser.binary();
 }
+fn literal_rule(parser: &mut Parser, _can_assign: bool) {
+    parser.literal();
+}
+fn string_rule(parser: &mut Parser, _can_assign: bool) {
+    parser.string();
+}
+fn variable_rule(parser: &mut Parser, can_assign: bool) {
+    parser.variable(can_assign);
+}
+fn call_rule(parser: &mut Parser, _can_assign: bool) {
+    parser.call();
+}
+fn and_rule(parser: &mut Parser, _can_assign: bool) {
+    parser.and();
+}
+fn or_rule(parser: &mut Parser, _can_assign: bool) {
+    parser.or();
+}
+fn dot_rule(parser: &mut Parser, can_assign: bool) {
+    parser.dot(can_assign);
+}
+fn this_rule(parser: &mut Parser, _can_assign: bool) {
+    parser.this();
+}
+fn super_rule(parser: &mut Parser, _can_assign: bool) {
+    parser.super_();
+}
 // You'll need more wrapper functions for other rules:
-// fn literal_string_rule(parser: &mut Parser) { parser.literal_string(); } // Implement parser.literal_string()
-// fn identifier_rule(parser: &mut Parser) { parser.identifier(); } // Implement parser.identifier()
-// fn dot_rule(parser: &mut Parser) { parser.dot(); } // Implement parser.dot() for method calls/property access
-// fn call_rule(parser: &mut Parser) { parser.call(); } // Implement parser.call() for function calls
-// fn and_rule(parser: &mut Parser) { parser.and(); } // Implement parser.and() for logical AND
-// fn or_rule(parser: &mut Parser) { parser.or(); } // Implement parser.or() for logical OR
-// fn assignment_rule(parser: &mut Parser) { parser.assignment(); } // Implement parser.assignment() for =
 // ... and so on for any token that can start an expression (prefix)
 // or appear between expressions (infix).
 
-// Placeholder for methods not yet implemented, add these to impl Parser later:
-// fn literal_string(&mut self) { /* ... */ }
-// fn identifier(&mut self) { /* ... */ }
-// fn dot(&mut self) { /* ... */ }
-// fn call(&mut self) { /* ... */ }
-// fn and(&mut self) { /* ... */ }
-// fn or(&mut self) { parser.or(); }
-// fn assignment(&mut self) { /* ... */ }
-// ... (previous imports, structs, wrapper functions)
-
 // --- Parse Rule Table ---
 // This table maps TokenType to ParseRule structs.
 // It defines how each token is parsed based on its position (prefix/infix)
@@ -94,7 +194,7 @@ static PARSE_RULES: [ParseRule; TokenType::Count as usize] = [
     /* TokenType::LeftParen    */
     ParseRule {
         prefix: Some(grouping_rule),
-        infix: None,
+        infix: Some(call_rule),
         precedence: Precedence::Call,
     }, // '(' can start a group or be part of a function call
     /* TokenType::RightParen   */
@@ -124,7 +224,7 @@ static PARSE_RULES: [ParseRule; TokenType::Count as usize] = [
     /* TokenType::Dot          */
     ParseRule {
         prefix: None,
-        infix: None,
+        infix: Some(dot_rule),
         precedence: Precedence::Call,
     }, // '.' for property access/method calls
     /* TokenType::Minus        */
@@ -173,8 +273,8 @@ static PARSE_RULES: [ParseRule; TokenType::Count as usize] = [
     ParseRule {
         prefix: None,
         infix: None,
-        precedence: Precedence::Assignment,
-    }, // '=' as assignment operator
+        precedence: Precedence::None,
+    }, // '=' is consumed by assignable prefix rules, never as an infix operator
     /* TokenType::EqualEqual   */
     ParseRule {
         prefix: None,
@@ -207,13 +307,13 @@ static PARSE_RULES: [ParseRule; TokenType::Count as usize] = [
     },
     /* TokenType::Identifier   */
     ParseRule {
-        prefix: None,
+        prefix: Some(variable_rule),
         infix: None,
         precedence: Precedence::None,
     },
     /* TokenType::String       */
     ParseRule {
-        prefix: None,
+        prefix: Some(string_rule),
         infix: None,
         precedence: Precedence::None,
     },
@@ -226,7 +326,7 @@ static PARSE_RULES: [ParseRule; TokenType::Count as usize] = [
     /* TokenType::And          */
     ParseRule {
         prefix: None,
-        infix: None,
+        infix: Some(and_rule),
         precedence: Precedence::And,
     },
     /* TokenType::Class        */
@@ -243,10 +343,10 @@ static PARSE_RULES: [ParseRule; TokenType::Count as usize] = [
     }, // Not used in expressions
     /* TokenType::False        */
     ParseRule {
-        prefix: None,
+        prefix: Some(literal_rule),
         infix: None,
         precedence: Precedence::None,
-    }, // Implement parser.literal_false()
+    },
     /* TokenType::For          */
     ParseRule {
         prefix: None,
@@ -267,14 +367,14 @@ static PARSE_RULES: [ParseRule; TokenType::Count as usize] = [
     }, // Not used in expressions
     /* TokenType::Nil          */
     ParseRule {
-        prefix: None,
+        prefix: Some(literal_rule),
         infix: None,
         precedence: Precedence::None,
-    }, // Implement parser.literal_nil()
+    },
     /* TokenType::Or           */
     ParseRule {
         prefix: None,
-        infix: None,
+        infix: Some(or_rule),
         precedence: Precedence::Or,
     },
     /* TokenType::Print        */
@@ -291,16 +391,22 @@ static PARSE_RULES: [ParseRule; TokenType::Count as usize] = [
     }, // Not used in expressions
     /* TokenType::Super        */
     ParseRule {
-        prefix: None,
+        prefix: Some(super_rule),
+        infix: None,
+        precedence: Precedence::None,
+    },
+    /* TokenType::This         */
+    ParseRule {
+        prefix: Some(this_rule),
         infix: None,
         precedence: Precedence::None,
-    }, // Implement parser.super_rule()
+    },
     /* TokenType::True         */
     ParseRule {
-        prefix: None,
+        prefix: Some(literal_rule),
         infix: None,
         precedence: Precedence::None,
-    }, // Implement parser.literal_true()
+    },
     /* TokenType::Var          */
     ParseRule {
         prefix: None,
@@ -325,85 +431,468 @@ static PARSE_RULES: [ParseRule; TokenType::Count as usize] = [
         infix: None,
         precedence: Precedence::None,
     }, // Handled explicitly in compile
-    /* TokenType::Count        */
-    ParseRule {
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    }, // Placeholder, never used for lookup
 ];
 
 // ... (previous imports, enums, ParseFn, ParseRule, wrapper functions, PARSE_RULES, get_rule)
 
-impl Parser {
+impl<'a> Parser<'a> {
     /* ========== 构造函数 ========== */
-    // Constructor now takes source and chunk, and initializes the scanner
-    pub fn new(source: String) -> Self {
+    // Constructor now takes source and the VM whose heap receives the compiled objects
+    pub fn new(source: String, vm: &'a mut VM) -> Self {
         // Changed order to match typical usage
         Parser {
             current: Token {
                 kind: token_type::TokenType::Eof,
                 lexeme: "".to_string(),
                 line: 0,
+                column: 0,
+                span: Span::default(),
             },
             previous: Token {
                 kind: token_type::TokenType::Eof,
                 lexeme: "".to_string(),
                 line: 0,
+                column: 0,
+                span: Span::default(),
             },
-            compiling_chunk: Chunk::new(), // Create the chunk here
             scanner: Scanner::new(source), // Initialize the scanner here
             had_error: false,
+            diagnostics: Vec::new(),
+            panic_mode: false,
+            compilers: vec![Compiler::new(FunctionType::Script, None)],
+            classes: Vec::new(),
+            vm,
         }
     }
 
     /* ========== 主要编译入口 ========== */
-    pub fn compile(&mut self) -> Result<Chunk, String> {
+    pub fn compile(&mut self) -> Result<GcRef<Function>, Vec<Diagnostic>> {
         // Does not take source or chunk now, they are owned by self
         self.had_error = false;
-        // Note: panic_mode is sometimes used to track if we are currently
-        // in a state where we are recovering from an error.
-        // let mut panic_mode = false; // Could add a field for this if needed
+        self.panic_mode = false;
 
         self.advance(); // Get the first token
 
-        // In a real compiler, you would loop through declarations here:
-        // while !self.match_token(TokenType::Eof) { // Need a match_token helper or check current.kind
-        //    self.declaration(); // Implement statement/declaration parsing rules
-        //    // If a parsing rule set the error flag, enter panic mode and synchronize
-        //    if self.had_error && !panic_mode {
-        //        panic_mode = true;
-        //        self.synchronize();
-        //    }
-        //    if panic_mode && !self.check(TokenType::Semicolon) && !self.check(/* sync token */) {
-        //        // Stay in panic mode, keep skipping if not at a sync point
-        //    } else {
-        //        // Exit panic mode if we hit a semicolon or sync token
-        //        panic_mode = false;
-        //    }
-        // }
-
-        // For a simple expression-only compiler:
-        self.expression(); // Parse the single expression
-        self.consume(TokenType::Eof, "Expect end of expression.".to_string()); // Expect EOF after the expression
-
-        // Emit the final return instruction *after* the expression is compiled
-        // and after consuming EOF (if successful).
-        // This assumes the expression leaves a value on the stack that should be returned.
-        // If there was a parsing error, we might not want to emit return,
-        // or handle it differently.
-        // Only emit return if no parsing error occurred before EOF.
-        if !self.had_error {
-            self.emit_return();
+        // A program is a sequence of declarations. Each declaration recovers
+        // from its own errors, so every error in the file gets reported.
+        while !self.match_token(TokenType::Eof) {
+            self.declaration();
         }
 
+        // The script ends with an implicit return.
+        let function = self.end_compiler();
+
         if self.had_error {
-            // Error messages were printed by error_* methods.
-            Err("Parsing failed.".to_string())
+            // Every error was recorded by the error_* methods.
+            Err(std::mem::take(&mut self.diagnostics))
         } else {
             // No parsing errors.
-            Ok(std::mem::take(&mut self.compiling_chunk))
+            Ok(self.alloc(function))
+        }
+    }
+
+    /* ========== 内存分配 ========== */
+
+    // Allocates through the VM's heap. A collection triggered here must also keep
+    // the functions still being compiled alive, since only the compiler can reach them.
+    fn alloc<T: HeapObject>(&mut self, object: T) -> GcRef<T> {
+        let reference = self.vm.heap.alloc(object);
+        if self.vm.heap.should_collect() {
+            self.vm.heap.mark_object(reference);
+            for compiler in &self.compilers {
+                self.vm.heap.mark_function_contents(&compiler.function);
+            }
+            self.vm.collect_garbage();
+        }
+        reference
+    }
+
+    // Same as `VM::intern`, but allocates through `Parser::alloc` so a collection
+    // can't free the functions being compiled.
+    fn copy_string(&mut self, chars: String) -> GcRef<LoxString> {
+        let hash = hash_string(&chars);
+        if let Some(interned) = self.vm.strings.find_string(&chars, hash, &self.vm.heap) {
+            return interned;
+        }
+        let string = self.alloc(LoxString::new(chars, hash));
+        self.vm.strings.set(string, hash, ());
+        string
+    }
+
+    /* ========== 函数编译上下文 ========== */
+
+    fn compiler(&self) -> &Compiler {
+        self.compilers.last().expect("no active compiler")
+    }
+
+    fn compiler_mut(&mut self) -> &mut Compiler {
+        self.compilers.last_mut().expect("no active compiler")
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.compiler_mut().function.chunk
+    }
+
+    // Finishes the innermost function and returns it.
+    fn end_compiler(&mut self) -> Function {
+        self.emit_return();
+        let mut compiler = self.compilers.pop().expect("no active compiler");
+        // Code with errors is never run, and may not even be well-formed.
+        if self.vm.optimize && !self.had_error {
+            optimize::optimize(&mut compiler.function.chunk);
+        }
+        compiler.function
+    }
+
+    /* ========== 声明与语句 ========== */
+
+    fn declaration(&mut self) {
+        if self.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_token(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
+        // If the statement reported an error, skip ahead to the next statement boundary.
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        // `class` has already been consumed.
+        self.consume(TokenType::Identifier, "Expect class name.".to_string());
+        let class_name = self.previous.clone();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit_op_with_index(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if self.match_token(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.".to_string());
+            self.variable(false);
+            if class_name.lexeme == self.previous.lexeme {
+                self.error("A class can't inherit from itself.".to_string());
+            }
+
+            // Bind the superclass to a hidden local named `super` in a scope
+            // wrapping the class body, so methods capture it as an upvalue.
+            self.begin_scope();
+            self.add_local(synthetic_token("super"));
+            self.define_variable(0);
+
+            self.named_variable(class_name.clone(), false);
+            self.emit_op(OpCode::Inherit);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        // Load the class back onto the stack so each method can be attached to it.
+        self.named_variable(class_name, false);
+        self.consume(
+            TokenType::LeftBrace,
+            "Expect '{' before class body.".to_string(),
+        );
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(
+            TokenType::RightBrace,
+            "Expect '}' after class body.".to_string(),
+        );
+        self.emit_op(OpCode::Pop);
+
+        if self
+            .classes
+            .last()
+            .is_some_and(|class| class.has_superclass)
+        {
+            self.end_scope();
+        }
+        self.classes.pop();
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.".to_string());
+        let constant = self.identifier_constant(&self.previous.clone());
+        let kind = if self.previous.lexeme == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(kind);
+        self.emit_op_with_index(OpCode::Method, constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        // `fun` has already been consumed.
+        let global = self.parse_variable("Expect function name.".to_string());
+        // A function may refer to itself, so it is usable before its body is compiled.
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    // Compiles a function's parameters and body in a fresh compiler context,
+    // then emits the finished function as a constant in the enclosing chunk.
+    fn function(&mut self, kind: FunctionType) {
+        let name = self.copy_string(self.previous.lexeme.clone());
+        self.compilers.push(Compiler::new(kind, Some(name)));
+        self.begin_scope();
+
+        self.consume(
+            TokenType::LeftParen,
+            "Expect '(' after function name.".to_string(),
+        );
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.compiler_mut().function.arity += 1;
+                if self.compiler().function.arity > MAX_ARGS {
+                    self.error_at_current(format!("Can't have more than {} parameters.", MAX_ARGS));
+                }
+                let constant = self.parse_variable("Expect parameter name.".to_string());
+                self.define_variable(constant);
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            TokenType::RightParen,
+            "Expect ')' after parameters.".to_string(),
+        );
+        self.consume(
+            TokenType::LeftBrace,
+            "Expect '{' before function body.".to_string(),
+        );
+        self.block();
+
+        // No end_scope: the frame's slots are discarded wholesale on return.
+        let function = self.end_compiler();
+        let function = self.alloc(function);
+        let constant = self.make_constant(Value::from(function));
+        self.emit_op_with_index(OpCode::Closure, constant);
+    }
+
+    fn var_declaration(&mut self) {
+        // `var` has already been consumed.
+        let global = self.parse_variable("Expect variable name.".to_string());
+
+        if self.match_token(TokenType::Equal) {
+            self.expression();
+        } else {
+            // `var a;` implicitly initializes the variable to nil.
+            self.emit_op(OpCode::Nil);
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.".to_string(),
+        );
+
+        self.define_variable(global);
+    }
+
+    fn statement(&mut self) {
+        if self.match_token(TokenType::Print) {
+            self.print_statement();
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_token(TokenType::If) {
+            self.if_statement();
+        } else if self.match_token(TokenType::While) {
+            self.while_statement();
+        } else if self.match_token(TokenType::For) {
+            self.for_statement();
+        } else if self.match_token(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn block(&mut self) {
+        // `{` has already been consumed.
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.".to_string());
+    }
+
+    fn begin_scope(&mut self) {
+        self.compiler_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.compiler_mut().scope_depth -= 1;
+
+        // Discard the locals declared in the scope we are leaving.
+        let scope_depth = self.compiler().scope_depth;
+        while let Some(local) = self.compiler().locals.last() {
+            if local.depth.is_some_and(|depth| depth <= scope_depth) {
+                break;
+            }
+            // Captured locals outlive the scope: hoist them onto the heap instead of dropping them.
+            if local.is_captured {
+                self.emit_op(OpCode::CloseUpvalue);
+            } else {
+                self.emit_op(OpCode::Pop);
+            }
+            self.compiler_mut().locals.pop();
+        }
+    }
+
+    fn print_statement(&mut self) {
+        // `print` has already been consumed.
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.".to_string());
+        self.emit_op(OpCode::Print);
+    }
+
+    fn return_statement(&mut self) {
+        // `return` has already been consumed.
+        if self.compiler().kind == FunctionType::Script {
+            self.error("Can't return from top-level code.".to_string());
         }
+
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.compiler().kind == FunctionType::Initializer {
+                self.error_with_note(
+                    "Can't return a value from an initializer.".to_string(),
+                    "An initializer always returns 'this'.".to_string(),
+                );
+            }
+            self.expression();
+            self.consume(
+                TokenType::Semicolon,
+                "Expect ';' after return value.".to_string(),
+            );
+            self.emit_op(OpCode::Return);
+        }
+    }
+
+    fn if_statement(&mut self) {
+        // `if` has already been consumed.
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.".to_string());
+        self.expression();
+        self.consume(
+            TokenType::RightParen,
+            "Expect ')' after condition.".to_string(),
+        );
+
+        // The condition stays on the stack across the jump, so each branch pops it.
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_op(OpCode::Pop);
+
+        if self.match_token(TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self) {
+        // `while` has already been consumed.
+        let loop_start = self.current_chunk().code.len();
+        self.consume(
+            TokenType::LeftParen,
+            "Expect '(' after 'while'.".to_string(),
+        );
+        self.expression();
+        self.consume(
+            TokenType::RightParen,
+            "Expect ')' after condition.".to_string(),
+        );
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_op(OpCode::Pop);
+    }
+
+    // `for` is desugared into the equivalent `while` loop:
+    // initializer, then condition check, body, increment, jump back to the condition.
+    fn for_statement(&mut self) {
+        // `for` has already been consumed.
+        // A variable declared in the initializer is scoped to the loop.
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.".to_string());
+        if self.match_token(TokenType::Semicolon) {
+            // No initializer.
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+        let mut exit_jump = None;
+        if !self.match_token(TokenType::Semicolon) {
+            self.expression();
+            self.consume(
+                TokenType::Semicolon,
+                "Expect ';' after loop condition.".to_string(),
+            );
+
+            // Jump out of the loop if the condition is false.
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_op(OpCode::Pop); // Condition.
+        }
+
+        if !self.match_token(TokenType::RightParen) {
+            // The increment is textually before the body but runs after it,
+            // so jump over it now and loop back to it from the end of the body.
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.current_chunk().code.len();
+            self.expression();
+            self.emit_op(OpCode::Pop);
+            self.consume(
+                TokenType::RightParen,
+                "Expect ')' after for clauses.".to_string(),
+            );
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_op(OpCode::Pop); // Condition.
+        }
+        self.end_scope();
+    }
+
+    fn expression_statement(&mut self) {
+        // The expression is evaluated for its side effects, so discard the result.
+        self.expression();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after expression.".to_string(),
+        );
+        self.emit_op(OpCode::Pop);
     }
 
     /* ========== 核心解析方法 (Pratt Parser) ========== */
@@ -427,8 +916,11 @@ impl Parser {
         let prefix_rule = get_rule(self.previous.kind); // Get the rule for the prefix token
 
         // 1. Handle the prefix part
+        // Only a low-precedence context may treat a following `=` as assignment,
+        // otherwise `a + b = c` would parse as `a + (b = c)`.
+        let can_assign = precedence <= Precedence::Assignment;
         if let Some(prefix_fn) = prefix_rule.prefix {
-            prefix_fn(self); // Call the prefix parse function
+            prefix_fn(self, can_assign); // Call the prefix parse function
         } else {
             // If no prefix function is defined for this token, it's a syntax error
             self.error("Expect expression.".to_string());
@@ -452,7 +944,7 @@ impl Parser {
 
             // Call the infix parse function for the now `self.previous` token
             if let Some(infix_fn) = infix_rule.infix {
-                infix_fn(self); // Call the infix parse function
+                infix_fn(self, can_assign); // Call the infix parse function
             } else {
                 // This should not happen if the precedence check passed,
                 // but as a safeguard.
@@ -463,14 +955,11 @@ impl Parser {
             // higher precedence for the right-hand operand.
         }
 
-        // Special handling for assignment's right-associativity can be needed.
-        // If the precedence we just finished parsing is Assignment or lower,
-        // and the *current* token is "=", it's an unexpected assignment
-        // that wasn't consumed by the assignment_rule.
-        // This check is sometimes placed here or within the assignment_rule.
-        // if precedence <= Precedence::Assignment && self.check(TokenType::Equal) {
-        //     self.error("Invalid assignment target.".to_string());
-        // }
+        // If assignment was allowed here but no rule consumed the "=",
+        // the left-hand side wasn't something that can be assigned to.
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.error("Invalid assignment target.".to_string());
+        }
     }
 
     // --- Specific Parsing Rules (called by wrapper functions) ---
@@ -487,7 +976,7 @@ impl Parser {
             0.0 // Provide a default value to continue parsing
         });
         // Emit bytecode to push the number constant onto the stack.
-        self.emit_constant(Value::Number(value));
+        self.emit_constant(Value::number(value));
     }
 
     fn grouping(&mut self) {
@@ -514,8 +1003,8 @@ impl Parser {
 
         // Emit the unary operation bytecode after the operand's code has been emitted.
         match operator.kind {
-            TokenType::Minus => self.emit_byte(OpCode::Negate),
-            // TokenType::Bang => self.emit_byte(OpCode::Not), // Assuming you have a Not opcode
+            TokenType::Minus => self.emit_op(OpCode::Negate),
+            TokenType::Bang => self.emit_op(OpCode::Not),
             _ => self.error(format!("Unexpected unary operator: {}", operator.lexeme)), // Should not happen if table is correct
         }
     }
@@ -548,81 +1037,407 @@ impl Parser {
 
         // Emit the binary operation bytecode after both operands' code.
         match operator.kind {
-            TokenType::Plus => self.emit_byte(OpCode::Add),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract),
-            TokenType::Star => self.emit_byte(OpCode::Multiply),
-            TokenType::Slash => self.emit_byte(OpCode::Divide),
-            // Add cases for comparison operators
-            // TokenType::BangEqual => self.emit_bytes(OpCode::Equal, OpCode::Not), // Emit Equal then Not for !=
-            // TokenType::EqualEqual => self.emit_byte(OpCode::Equal),
-            // TokenType::Greater => self.emit_byte(OpCode::Greater),
-            // TokenType::GreaterEqual => self.emit_bytes(OpCode::Less, OpCode::Not), // Emit Less then Not for >=
-            // TokenType::Less => self.emit_byte(OpCode::Less),
-            // TokenType::LessEqual => self.emit_bytes(OpCode::Greater, OpCode::Not), // Emit Greater then Not for <=
+            TokenType::Plus => self.emit_op(OpCode::Add),
+            TokenType::Minus => self.emit_op(OpCode::Subtract),
+            TokenType::Star => self.emit_op(OpCode::Multiply),
+            TokenType::Slash => self.emit_op(OpCode::Divide),
+            TokenType::BangEqual => self.emit_ops(OpCode::Equal, OpCode::Not), // Emit Equal then Not for !=
+            TokenType::EqualEqual => self.emit_op(OpCode::Equal),
+            TokenType::Greater => self.emit_op(OpCode::Greater),
+            TokenType::GreaterEqual => self.emit_ops(OpCode::Less, OpCode::Not), // Emit Less then Not for >=
+            TokenType::Less => self.emit_op(OpCode::Less),
+            TokenType::LessEqual => self.emit_ops(OpCode::Greater, OpCode::Not), // Emit Greater then Not for <=
             // Add cases for logical operators (handled differently, see and/or rules)
             _ => self.error(format!("Unexpected binary operator: {}", operator.lexeme)), // Should not happen if table is correct
         }
     }
 
-    // Implement placeholder methods for other rules referenced in the table:
-    // fn literal_string(&mut self) { /* ... */ }
-    // fn identifier(&mut self) { /* ... */ }
-    // fn dot(&mut self) { /* ... */ }
-    // fn call(&mut self) { /* ... */ }
-    // fn and(&mut self) {
-    //    // AND has short-circuiting behavior, requires different bytecode than simple binary ops
-    //    // Parse left operand, then emit jump if false, parse right operand, etc.
-    //    let end_jump = self.emit_jump(OpCode::JumpIfFalse); // Implement emit_jump
-    //    self.emit_byte(OpCode::Pop); // Pop left operand's value if true
-    //    self.parse_precedence(Precedence::And); // Parse right operand
-    //    self.patch_jump(end_jump); // Implement patch_jump
-    // }
-    // fn or(&mut self) { /* Similar short-circuiting logic */ }
-    // fn assignment(&mut self) { /* ... */ }
-    // fn literal_false(&mut self) { self.emit_byte(OpCode::False); }
-    // fn literal_nil(&mut self) { self.emit_byte(OpCode::Nil); }
-    // fn literal_true(&mut self) { self.emit_byte(OpCode::True); }
-    // fn super_rule(&mut self) { /* ... */ } // For 'super' keyword
-    // fn self_rule(&mut self) { /* ... */ } // For 'this' keyword (often 'self' in Rust examples)
+    fn literal(&mut self) {
+        // `self.previous` is the keyword literal (`true`, `false` or `nil`).
+        match self.previous.kind {
+            TokenType::False => self.emit_op(OpCode::False),
+            TokenType::Nil => self.emit_op(OpCode::Nil),
+            TokenType::True => self.emit_op(OpCode::True),
+            _ => self.error(format!("Unexpected literal: {}", self.previous.lexeme)), // Should not happen if table is correct
+        }
+    }
+
+    fn string(&mut self) {
+        // `self.previous` is the string token. The scanner already stripped the quotes.
+        let string = self.copy_string(self.previous.lexeme.clone());
+        self.emit_constant(Value::from(string));
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        // `self.previous` is the identifier token.
+        self.named_variable(self.previous.clone(), can_assign);
+    }
+
+    fn named_variable(&mut self, name: Token, can_assign: bool) {
+        // Locals shadow upvalues, which shadow globals; anything not found in an
+        // enclosing function is assumed to be global.
+        let current = self.compilers.len() - 1;
+        // Slots and upvalue indexes fit in a byte thanks to MAX_LOCALS and MAX_UPVALUES.
+        let (get_op, set_op, arg) = if let Some(slot) = self.resolve_local(current, &name) {
+            (OpCode::GetLocal, OpCode::SetLocal, slot)
+        } else if let Some(index) = self.resolve_upvalue(current, &name) {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, index)
+        } else {
+            let arg = self.identifier_constant(&name);
+            (OpCode::GetGlobal, OpCode::SetGlobal, arg)
+        };
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_op_with_index(set_op, arg);
+        } else {
+            self.emit_op_with_index(get_op, arg);
+        }
+    }
+
+    fn and(&mut self) {
+        // AND has short-circuiting behavior: if the left operand is falsey it is
+        // the result, and the right operand is never evaluated.
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop); // Pop left operand's value if true
+        self.parse_precedence(Precedence::And); // Parse right operand
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self) {
+        // If the left operand is truthy, skip over the right operand.
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_op(OpCode::Pop);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn call(&mut self) {
+        // `self.previous` is the '(' following the callee expression.
+        let arg_count = self.argument_list();
+        self.emit_op_with(OpCode::Call, arg_count);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == MAX_ARGS {
+                    self.error(format!("Can't have more than {} arguments.", MAX_ARGS));
+                }
+                arg_count += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            TokenType::RightParen,
+            "Expect ')' after arguments.".to_string(),
+        );
+        arg_count as u8
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        // `self.previous` is the '.' following the instance expression.
+        self.consume(
+            TokenType::Identifier,
+            "Expect property name after '.'.".to_string(),
+        );
+        let name = self.identifier_constant(&self.previous.clone());
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_property(OpCode::SetProperty, name, &[]);
+        } else if self.match_token(TokenType::LeftParen) {
+            // `instance.method(...)` is called immediately: skip creating a bound method.
+            let arg_count = self.argument_list();
+            self.emit_property(OpCode::Invoke, name, &[arg_count]);
+        } else {
+            self.emit_property(OpCode::GetProperty, name, &[]);
+        }
+    }
+
+    fn this(&mut self) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.".to_string());
+            return;
+        }
+        // `this` is an ordinary local living in slot zero of every method,
+        // so it can be captured by closures like any other variable.
+        self.variable(false);
+    }
+
+    fn super_(&mut self) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class.".to_string()),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.".to_string())
+            }
+            Some(_) => {}
+        }
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.".to_string());
+        self.consume(
+            TokenType::Identifier,
+            "Expect superclass method name.".to_string(),
+        );
+        let name = self.identifier_constant(&self.previous.clone());
+
+        // The receiver is `this`; the class to start the method lookup from is the
+        // superclass captured in the hidden `super` variable.
+        self.named_variable(synthetic_token("this"), false);
+        if self.match_token(TokenType::LeftParen) {
+            // `super.method(...)` is called immediately: skip creating a bound method.
+            let arg_count = self.argument_list();
+            self.named_variable(synthetic_token("super"), false);
+            self.emit_op_with_index(OpCode::SuperInvoke, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable(synthetic_token("super"), false);
+            self.emit_op_with_index(OpCode::GetSuper, name);
+        }
+    }
 
     /* ========== 发出字节码 (Adjusted) ========== */
-    // These methods use self.compiling_chunk
-    fn emit_byte(&mut self, op_code: OpCode) {
-        // Changed byte to OpCode
-        self.compiling_chunk
-            .write_chunk(op_code, self.previous.line);
+    // These methods write into the chunk of the function currently being compiled
+    fn emit_byte(&mut self, byte: u8) {
+        let line = self.previous.line;
+        self.current_chunk().write(byte, line);
+    }
+    fn emit_op(&mut self, op: OpCode) {
+        self.emit_byte(op as u8);
+    }
+    // Emits an instruction with a one-byte operand.
+    fn emit_op_with(&mut self, op: OpCode, operand: u8) {
+        self.emit_op(op);
+        self.emit_byte(operand);
+    }
+    // Emits an instruction whose first operand is an index, switching to the
+    // long form (three index bytes) when the index doesn't fit in one.
+    fn emit_op_with_index(&mut self, op: OpCode, index: usize) {
+        match (u8::try_from(index), op.long_form()) {
+            (Ok(index), _) => self.emit_op_with(op, index),
+            (Err(_), Some(long)) => {
+                self.emit_op(long);
+                for byte in &index.to_be_bytes()[mem::size_of::<usize>() - 3..] {
+                    self.emit_byte(*byte);
+                }
+            }
+            // Local slots and upvalue indexes are kept below 256 when declared.
+            (Err(_), None) => unreachable!("{:?} operand {} doesn't fit in a byte", op, index),
+        }
     }
     // end_compilation might be called by the outer compilation loop, not expression
     // fn end_compilation(&mut self) {
     //     self.emit_return();
     // }
+    // Functions without an explicit return value implicitly return nil.
+    // Initializers return the instance being initialized, which lives in slot zero.
     fn emit_return(&mut self) {
-        self.emit_byte(OpCode::Return);
+        if self.compiler().kind == FunctionType::Initializer {
+            self.emit_op_with(OpCode::GetLocal, 0);
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+        self.emit_op(OpCode::Return);
+    }
+    // Each property access gets its own inline cache, named by a two-byte operand
+    // after the name and the instruction's other operands.
+    fn emit_property(&mut self, op: OpCode, name: usize, operands: &[u8]) {
+        let cache = self.current_chunk().caches.len();
+        if cache > u16::MAX as usize {
+            self.error("Too many property accesses in one function.".to_string());
+        }
+        self.current_chunk().caches.push(InlineCache::default());
+        self.emit_op_with_index(op, name);
+        for &operand in operands {
+            self.emit_byte(operand);
+        }
+        for byte in (cache as u16).to_be_bytes() {
+            self.emit_byte(byte);
+        }
     }
-    fn emit_bytes(&mut self, byte1: OpCode, byte2: OpCode) {
-        // Changed byte1/byte2 to OpCode
-        self.emit_byte(byte1);
-        self.emit_byte(byte2);
+    fn emit_ops(&mut self, op1: OpCode, op2: OpCode) {
+        self.emit_op(op1);
+        self.emit_op(op2);
     }
+    // Literals use OP_CONSTANT_LONG once the first 256 constants are taken.
     fn emit_constant(&mut self, value: Value) {
-        let constant_index = self.make_constant(value);
-        // OpCode::Constant takes the index as an operand.
-        // Need to ensure your OpCode enum handles operands, or emit the index separately.
-        // Example assuming OpCode::Constant variant takes usize:
-        self.emit_byte(OpCode::Constant(constant_index));
-        // The original example emitted OpCode::Return right after constant.
-        // This is only correct if the expression is just the constant.
-        // In a full expression, only one return is needed at the end of the function/script.
-        // So remove `OpCode::Return` here.
-        // self.emit_bytes(OpCode::Constant(constant_index), OpCode::Return); // REMOVE RETURN HERE
+        let index = self.make_constant(value);
+        self.emit_op_with_index(OpCode::Constant, index);
     }
     fn make_constant(&mut self, value: Value) -> usize {
-        self.compiling_chunk.add_constant(value)
+        let index = self.current_chunk().add_constant(value);
+        if index > MAX_CONSTANTS {
+            self.error("Too many constants in one chunk.".to_string());
+            return 0;
+        }
+        index
+    }
+    // Global variables are looked up by name at runtime, so the name is stored
+    // in the constant table and referenced by index.
+    fn identifier_constant(&mut self, name: &Token) -> usize {
+        let string = self.copy_string(name.lexeme.clone());
+        self.make_constant(Value::from(string))
+    }
+
+    // Returns the stack slot of the innermost local named `name` in the
+    // function compiled by `self.compilers[compiler]`, if any.
+    fn resolve_local(&mut self, compiler: usize, name: &Token) -> Option<usize> {
+        let (slot, initialized) = self.compilers[compiler]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name.lexeme == name.lexeme)
+            .map(|(slot, local)| (slot, local.depth.is_some()))?;
+        if !initialized {
+            self.error("Can't read local variable in its own initializer.".to_string());
+        }
+        Some(slot)
+    }
+
+    fn add_local(&mut self, name: Token) {
+        if self.compiler().locals.len() == MAX_LOCALS {
+            self.error("Too many local variables in function.".to_string());
+            return;
+        }
+        self.compiler_mut().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
     }
 
-    // You'll likely need emit_jump and patch_jump for control flow (if, while, and, or)
-    // fn emit_jump(&mut self, opcode: OpCode) -> usize { /* Emit jump instruction, placeholder for offset, return address */ }
-    // fn patch_jump(&mut self, offset: usize) { /* Write the correct jump offset at the placeholder */ }
+    // Resolves `name` as a variable captured from an enclosing function,
+    // threading the upvalue through every function in between.
+    // Returns its index in the upvalue array of `self.compilers[compiler]`.
+    fn resolve_upvalue(&mut self, compiler: usize, name: &Token) -> Option<usize> {
+        if compiler == 0 {
+            return None; // The top-level script has no enclosing function
+        }
+        let enclosing = compiler - 1;
+        if let Some(local) = self.resolve_local(enclosing, name) {
+            self.compilers[enclosing].locals[local].is_captured = true;
+            return Some(self.add_upvalue(compiler, local, true));
+        }
+        if let Some(upvalue) = self.resolve_upvalue(enclosing, name) {
+            return Some(self.add_upvalue(compiler, upvalue, false));
+        }
+        None
+    }
+
+    fn add_upvalue(&mut self, compiler: usize, index: usize, is_local: bool) -> usize {
+        let upvalue = UpvalueRef { is_local, index };
+        let upvalues = &self.compilers[compiler].function.upvalues;
+        // A closure captures each variable once, however often it is referenced.
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return existing;
+        }
+        if upvalues.len() == MAX_UPVALUES {
+            self.error("Too many closure variables in function.".to_string());
+            return 0;
+        }
+        let upvalues = &mut self.compilers[compiler].function.upvalues;
+        upvalues.push(upvalue);
+        upvalues.len() - 1
+    }
+
+    // Records a local variable in the current scope. Globals are late bound,
+    // so there is nothing to declare for them.
+    fn declare_variable(&mut self) {
+        let scope_depth = self.compiler().scope_depth;
+        if scope_depth == 0 {
+            return;
+        }
+        let name = self.previous.clone();
+        let duplicate = self
+            .compiler()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .find(|local| local.name.lexeme == name.lexeme)
+            .map(|local| local.name.line);
+        if let Some(line) = duplicate {
+            self.error_with_note(
+                "Already a variable with this name in this scope.".to_string(),
+                format!("The previous declaration is on line {}.", line),
+            );
+        }
+        self.add_local(name);
+    }
+
+    fn parse_variable(&mut self, error_message: String) -> usize {
+        self.consume(TokenType::Identifier, error_message);
+
+        self.declare_variable();
+        if self.compiler().scope_depth > 0 {
+            return 0; // Locals live on the stack, no name constant is needed
+        }
+        self.identifier_constant(&self.previous.clone())
+    }
+
+    // Marks the most recently declared local as usable.
+    fn mark_initialized(&mut self) {
+        let compiler = self.compiler_mut();
+        if compiler.scope_depth == 0 {
+            return; // Globals have no local slot to mark
+        }
+        let scope_depth = compiler.scope_depth;
+        if let Some(local) = compiler.locals.last_mut() {
+            local.depth = Some(scope_depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.compiler().scope_depth > 0 {
+            // The initializer's value is already sitting in the local's stack slot.
+            self.mark_initialized();
+            return;
+        }
+        self.emit_op_with_index(OpCode::DefineGlobal, global);
+    }
+
+    // Emits a forward jump with a placeholder offset and returns the offset's
+    // position, so it can be filled in by `patch_jump` once the target is known.
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_op(instruction);
+        self.emit_byte(0xff);
+        self.emit_byte(0xff);
+        self.current_chunk().code.len() - 2
+    }
+
+    // Points the jump whose operand is at `offset` to the next instruction to be emitted.
+    // Offsets are relative to the instruction following the jump.
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 skips the operand itself.
+        let jump = self.current_chunk().code.len() - offset - 2;
+        let Ok(jump) = u16::try_from(jump) else {
+            self.error("Too much code to jump over.".to_string());
+            return;
+        };
+        let code = &mut self.current_chunk().code;
+        code[offset..offset + 2].copy_from_slice(&jump.to_be_bytes());
+    }
+
+    // Emits a backward jump to `loop_start`.
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_op(OpCode::Loop);
+        // +2 accounts for the operand, which the ip has already passed.
+        let offset = self.current_chunk().code.len() - loop_start + 2;
+        let offset = u16::try_from(offset).unwrap_or_else(|_| {
+            self.error("Loop body too large.".to_string());
+            0
+        });
+        for byte in offset.to_be_bytes() {
+            self.emit_byte(byte);
+        }
+    }
 
     /* ========== Token 流控制 (Using self.scanner) ========== */
     fn advance(&mut self) {
@@ -671,8 +1486,8 @@ impl Parser {
     // synchronize now uses self.scanner
     fn synchronize(&mut self) {
         // No scanner arg needed
-        // Exit panic mode (if you implement panic_mode flag)
-        // self.panic_mode = false;
+        // Exit panic mode: from here on, new errors are reported again.
+        self.panic_mode = false;
 
         // Keep advancing as long as we're not at EOF and haven't found a synchronization point.
         while self.current.kind != TokenType::Eof {
@@ -705,33 +1520,38 @@ impl Parser {
     }
 
     /* ========== 错误报告 (Using had_error) ========== */
-    // These methods now set the had_error flag and print, but return nothing.
+    // These methods set the had_error flag and record a diagnostic, but return nothing.
     fn error_at_current(&mut self, message: String) {
         // Use clone if error_at needs to own the token, otherwise pass reference.
         // Let's pass a reference for efficiency.
-        self.error_at(self.current.clone(), message);
+        self.error_at(self.current.clone(), message, None);
     }
 
     fn error(&mut self, message: String) {
         // Use clone if error_at needs to own the token, otherwise pass reference.
-        self.error_at(self.previous.clone(), message);
+        self.error_at(self.previous.clone(), message, None);
     }
 
-    // Changed signature to accept a reference to Token
-    fn error_at(&mut self, token: Token, message: String) {
-        // Don't report if we're already in a state where we know there's an error
-        // and haven't synchronized yet. (This is where a `panic_mode` field helps).
-        // if self.panic_mode { return; }
-        // self.panic_mode = true; // Enter panic mode when error is reported
+    fn error_with_note(&mut self, message: String, note: String) {
+        self.error_at(self.previous.clone(), message, Some(note));
+    }
 
-        eprintln!("[line {}] Error", token.line);
-        match token.kind {
-            TokenType::Eof => eprint!(" at end"),
-            TokenType::Error => {} // Error token's lexeme is the error message, already printed in advance's loop
-            _ => eprint!(" at '{}'", token.lexeme),
+    fn error_at(&mut self, token: Token, message: String, note: Option<String>) {
+        // Don't report if we're already in a state where we know there's an error
+        // and haven't synchronized yet.
+        if self.panic_mode {
+            return;
         }
-        eprintln!(": {}", message);
+        self.panic_mode = true; // Enter panic mode when error is reported
+
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            span: token.span,
+            line: token.line,
+            column: token.column,
+            message,
+            note,
+        });
         self.had_error = true; // <--- Set the error flag
-        // No return value anymore
     }
 }
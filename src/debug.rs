@@ -1,28 +1,348 @@
+// debug.rs
+// Human-readable bytecode. Two formats live here:
+//
+// * The trace format (`dissemble_instruction`), one instruction at a time with
+//   constants shown inline, used by `--trace`.
+//
+// * The program listing (`disassemble_program`), printed by `--disassemble`.
+//   Its format is stable so that it can be compared against golden files:
+//
+//     == <name> ==                 One section per function: the script first,
+//                                  then every function constant, depth-first in
+//                                  constant-table order. <name> is "script" for
+//                                  top-level code, else the function's name.
+//     arity <n>, upvalues <n>
+//     constants:
+//       <index> <literal>          Numbers as Lox prints them; strings in double
+//                                  quotes with \" \\ \n \r \t escaped; functions
+//                                  as <fn name>.
+//     code:
+//     L<n>:                        Label before each jump target, numbered from 0
+//                                  in offset order.
+//       <offset> <line> <OP_NAME> [operands] [; <literal>]
+//                                  Offset is the byte offset of the instruction,
+//                                  4 digits. Line is right-aligned in 4 columns,
+//                                  or "   |" when the previous instruction is on
+//                                  the same line. Jump operands are labels
+//                                  (`OP_JUMP L2`); constant operands are the
+//                                  index followed by `; ` and the literal.
+//                                  Superinstructions that read a local print
+//                                  the slot first (`OP_GET_LOCAL_ADD_CONSTANT
+//                                  1 0 ; 2`).
+//                                  Property accesses leave out their inline
+//                                  cache index, which is just their order.
+//                                  OP_INVOKE and OP_SUPER_INVOKE print the
+//                                  argument count, then the method name
+//                                  (`OP_INVOKE 1 3 ; "inc"`); OP_INVOKE's cache
+//                                  index is left out like a property access's.
+//                                  Long forms (`OP_GET_GLOBAL_LONG 276 ; "gl"`)
+//                                  print the same operands as their short forms.
+//                   local <n>      After OP_CLOSURE, one line per captured
+//                   upvalue <n>    variable, in capture order.
+//
+//   Sections are separated by a blank line.
+use std::collections::BTreeMap;
+
 use crate::chunk::{Chunk, OpCode};
-#[allow(unused)]
-pub fn dissemble_chunk(chunk: &Chunk, name: &str) {
-    println!("== {} ==", name);
-    for (i, op) in chunk.code.iter().enumerate() {
-        dissemble_instruction(i, op, chunk);
+use crate::memory::{GcRef, Heap};
+use crate::object::Function;
+use crate::value::{Unpacked, Value};
+
+/* ========== 执行跟踪格式 (Trace format) ========== */
+
+// One instruction as `offset line text`, where the line column shows `|`
+// when the instruction comes from the same source line as the previous one.
+pub fn dissemble_instruction(chunk: &Chunk, offset: usize, heap: &Heap) -> String {
+    format!(
+        "{:04} {} {}",
+        offset,
+        line_column(chunk, offset),
+        instruction_text(chunk, offset, heap)
+    )
+}
+
+// The instruction's name and operands, e.g. `OP_CONSTANT 1.5` or `OP_JUMP 3 -> 9`.
+// Constants may be heap objects, so the heap is needed to print them.
+pub fn instruction_text(chunk: &Chunk, offset: usize, heap: &Heap) -> String {
+    let Some(op) = chunk.op(offset) else {
+        return format!("Unknown opcode {}", chunk.code[offset]);
+    };
+    let mut text = op_name(op).to_string();
+    for operand in operands(chunk, offset, op) {
+        let operand = match operand {
+            Operand::Byte(byte) => byte.to_string(),
+            Operand::ArgCount(count) => format!("({} args)", count),
+            Operand::Constant(index) => chunk.constants[index].display(heap).to_string(),
+            Operand::Jump(target) => format!("{} -> {}", offset, target),
+        };
+        text.push(' ');
+        text.push_str(&operand);
+    }
+    // The upvalues a closure captures are listed on the lines below it.
+    for capture in captures(chunk, offset, op, heap) {
+        text.push_str(&format!("\n   |                     {}", capture));
     }
+    text
 }
-pub fn dissemble_instruction(i: usize, op: &OpCode, chunk: &Chunk) {
-    print!("{:04} ", i);
-    if i > 0 && chunk.line_numbers[i] == chunk.line_numbers[i - 1] {
-        print!("   | ");
-    } else {
-        print!("{:4} ", chunk.line_numbers[i]);
+
+/* ========== 程序清单格式 (Program listing) ========== */
+
+// Disassembles `function` and every function nested in it, in the format
+// described at the top of this file.
+pub fn disassemble_program(function: GcRef<Function>, heap: &Heap) -> String {
+    let mut sections = Vec::new();
+    collect_sections(function, heap, &mut sections);
+    sections.join("\n")
+}
+
+fn collect_sections(function: GcRef<Function>, heap: &Heap, sections: &mut Vec<String>) {
+    sections.push(dissemble_chunk(&heap[function], heap));
+    for constant in &heap[function].chunk.constants {
+        if let Unpacked::Function(nested) = constant.unpack() {
+            collect_sections(nested, heap, sections);
+        }
+    }
+}
+
+// One section of the program listing.
+pub fn dissemble_chunk(function: &Function, heap: &Heap) -> String {
+    let chunk = &function.chunk;
+    let name = match function.name {
+        Some(name) => heap[name].chars.as_str(),
+        None => "script",
+    };
+    let mut out = format!("== {} ==\n", name);
+    out.push_str(&format!(
+        "arity {}, upvalues {}\n",
+        function.arity,
+        function.upvalues.len()
+    ));
+
+    out.push_str("constants:\n");
+    for (index, constant) in chunk.constants.iter().enumerate() {
+        out.push_str(&format!("  {} {}\n", index, literal(constant, heap)));
+    }
+
+    // Label jump targets in offset order, so labels read top to bottom.
+    let mut labels = BTreeMap::new();
+    for offset in chunk.offsets() {
+        if chunk.op(offset).is_some_and(OpCode::is_jump) {
+            labels.insert(chunk.jump_target(offset), 0);
+        }
     }
+    for (number, label) in labels.values_mut().enumerate() {
+        *label = number;
+    }
+
+    out.push_str("code:\n");
+    for offset in chunk.offsets() {
+        if let Some(label) = labels.get(&offset) {
+            out.push_str(&format!("L{}:\n", label));
+        }
+        out.push_str(&format!("  {:04} {} ", offset, line_column(chunk, offset)));
+        out.push_str(&listing_text(chunk, offset, heap, &labels));
+        out.push('\n');
+    }
+    out
+}
+
+fn listing_text(
+    chunk: &Chunk,
+    offset: usize,
+    heap: &Heap,
+    labels: &BTreeMap<usize, usize>,
+) -> String {
+    let Some(op) = chunk.op(offset) else {
+        return format!("Unknown opcode {}", chunk.code[offset]);
+    };
+    let mut text = op_name(op).to_string();
+    for operand in operands(chunk, offset, op) {
+        let operand = match operand {
+            Operand::Byte(byte) | Operand::ArgCount(byte) => byte.to_string(),
+            Operand::Constant(index) => {
+                format!("{} ; {}", index, literal(&chunk.constants[index], heap))
+            }
+            Operand::Jump(target) => format!("L{}", labels[&target]),
+        };
+        text.push(' ');
+        text.push_str(&operand);
+    }
+    for capture in captures(chunk, offset, op, heap) {
+        text.push_str(&format!("\n                 {}", capture));
+    }
+    text
+}
+
+/* ========== 操作数 (Operands) ========== */
+
+// An instruction operand, decoded once and printed differently by each format.
+enum Operand {
+    Byte(u8),        // Local slot, upvalue index, argument or pop count
+    ArgCount(u8),    // Argument count of a method invocation
+    Constant(usize), // Constant index
+    Jump(usize),     // Offset of the jump target
+}
+
+// The operands both formats show, in order. Constants always come last, so
+// the listing's `; <literal>` comment ends the line.
+fn operands(chunk: &Chunk, offset: usize, op: OpCode) -> Vec<Operand> {
+    let constant = || Operand::Constant(constant_index(chunk, offset, op));
+    // Long forms print like their short forms, with a wider index.
+    let after_index = offset + if op.is_long() { 4 } else { 2 };
+    match op.short_form() {
+        OpCode::Constant
+        | OpCode::DefineGlobal
+        | OpCode::GetGlobal
+        | OpCode::SetGlobal
+        | OpCode::Class
+        | OpCode::GetProperty
+        | OpCode::SetProperty
+        | OpCode::Method
+        | OpCode::GetSuper
+        | OpCode::Closure
+        | OpCode::AddConstant
+        | OpCode::SubtractConstant => vec![constant()],
+        OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call
+        | OpCode::PopN => vec![Operand::Byte(chunk.code[offset + 1])],
+        OpCode::GetLocalAddConstant | OpCode::GetLocalSubtractConstant => {
+            vec![Operand::Byte(chunk.code[offset + 1]), constant()]
+        }
+        op if op.is_jump() => vec![Operand::Jump(chunk.jump_target(offset))],
+        OpCode::Invoke | OpCode::SuperInvoke => {
+            vec![Operand::ArgCount(chunk.code[after_index]), constant()]
+        }
+        _ => Vec::new(),
+    }
+}
+
+// A constant as it appears in the listing.
+fn literal(value: &Value, heap: &Heap) -> String {
+    match value.unpack() {
+        Unpacked::String(string) => {
+            let mut out = String::from("\"");
+            for c in heap[string].chars.chars() {
+                match c {
+                    '"' => out.push_str("\\\""),
+                    '\\' => out.push_str("\\\\"),
+                    '\n' => out.push_str("\\n"),
+                    '\r' => out.push_str("\\r"),
+                    '\t' => out.push_str("\\t"),
+                    c => out.push(c),
+                }
+            }
+            out.push('"');
+            out
+        }
+        _ => value.display(heap).to_string(),
+    }
+}
+
+/* ========== 辅助函数 ========== */
+
+fn op_name(op: OpCode) -> &'static str {
+    match op {
+        OpCode::Return => "OP_RETURN",
+        OpCode::Constant => "OP_CONSTANT",
+        OpCode::ConstantLong => "OP_CONSTANT_LONG",
+        OpCode::Negate => "OP_NEGATE",
+        OpCode::Add => "OP_ADD",
+        OpCode::Subtract => "OP_SUBTRACT",
+        OpCode::Multiply => "OP_MULTIPLY",
+        OpCode::Divide => "OP_DIVIDE",
+        OpCode::Nil => "OP_NIL",
+        OpCode::True => "OP_TRUE",
+        OpCode::False => "OP_FALSE",
+        OpCode::Not => "OP_NOT",
+        OpCode::Equal => "OP_EQUAL",
+        OpCode::Greater => "OP_GREATER",
+        OpCode::Less => "OP_LESS",
+        OpCode::Print => "OP_PRINT",
+        OpCode::Pop => "OP_POP",
+        OpCode::DefineGlobal => "OP_DEFINE_GLOBAL",
+        OpCode::GetGlobal => "OP_GET_GLOBAL",
+        OpCode::SetGlobal => "OP_SET_GLOBAL",
+        OpCode::GetLocal => "OP_GET_LOCAL",
+        OpCode::SetLocal => "OP_SET_LOCAL",
+        OpCode::Jump => "OP_JUMP",
+        OpCode::JumpIfFalse => "OP_JUMP_IF_FALSE",
+        OpCode::Loop => "OP_LOOP",
+        OpCode::Call => "OP_CALL",
+        OpCode::Closure => "OP_CLOSURE",
+        OpCode::GetUpvalue => "OP_GET_UPVALUE",
+        OpCode::SetUpvalue => "OP_SET_UPVALUE",
+        OpCode::CloseUpvalue => "OP_CLOSE_UPVALUE",
+        OpCode::Class => "OP_CLASS",
+        OpCode::GetProperty => "OP_GET_PROPERTY",
+        OpCode::SetProperty => "OP_SET_PROPERTY",
+        OpCode::Method => "OP_METHOD",
+        OpCode::Inherit => "OP_INHERIT",
+        OpCode::GetSuper => "OP_GET_SUPER",
+        OpCode::Invoke => "OP_INVOKE",
+        OpCode::SuperInvoke => "OP_SUPER_INVOKE",
+        OpCode::DefineGlobalLong => "OP_DEFINE_GLOBAL_LONG",
+        OpCode::GetGlobalLong => "OP_GET_GLOBAL_LONG",
+        OpCode::SetGlobalLong => "OP_SET_GLOBAL_LONG",
+        OpCode::ClosureLong => "OP_CLOSURE_LONG",
+        OpCode::ClassLong => "OP_CLASS_LONG",
+        OpCode::GetPropertyLong => "OP_GET_PROPERTY_LONG",
+        OpCode::SetPropertyLong => "OP_SET_PROPERTY_LONG",
+        OpCode::MethodLong => "OP_METHOD_LONG",
+        OpCode::GetSuperLong => "OP_GET_SUPER_LONG",
+        OpCode::InvokeLong => "OP_INVOKE_LONG",
+        OpCode::SuperInvokeLong => "OP_SUPER_INVOKE_LONG",
+        OpCode::PopN => "OP_POP_N",
+        OpCode::AddConstant => "OP_ADD_CONSTANT",
+        OpCode::SubtractConstant => "OP_SUBTRACT_CONSTANT",
+        OpCode::GetLocalAddConstant => "OP_GET_LOCAL_ADD_CONSTANT",
+        OpCode::GetLocalSubtractConstant => "OP_GET_LOCAL_SUBTRACT_CONSTANT",
+        OpCode::JumpIfNotLess => "OP_JUMP_IF_NOT_LESS",
+        OpCode::JumpIfNotGreater => "OP_JUMP_IF_NOT_GREATER",
+        OpCode::JumpIfNotEqual => "OP_JUMP_IF_NOT_EQUAL",
+    }
+}
+
+// The constant operand of the instruction at `offset`.
+fn constant_index(chunk: &Chunk, offset: usize, op: OpCode) -> usize {
     match op {
-        OpCode::Return => println!("OP_RETURN"),
-        OpCode::Constant(index) => {
-            println!("OP_CONSTANT {}", chunk.constants[*index]);
+        op if op.is_long() => chunk.read_u24(offset + 1),
+        // After the local slot.
+        OpCode::GetLocalAddConstant | OpCode::GetLocalSubtractConstant => {
+            chunk.code[offset + 2] as usize
         }
-        OpCode::Negate => println!("OP_NEGATE"),
-        OpCode::Add => println!("OP_ADD"),
-        OpCode::Subtract => println!("OP_SUBTRACT"),
-        OpCode::Multiply => println!("OP_MULTIPLY"),
-        OpCode::Divide => println!("OP_DIVIDE"),
-        // Add more OpCode cases here as needed
+        _ => chunk.code[offset + 1] as usize,
+    }
+}
+
+// The line column: the source line, or `|` if it's the same as the previous instruction's.
+// Every byte of an instruction has the same line, so the byte before `offset`
+// tells the line of the previous instruction.
+fn line_column(chunk: &Chunk, offset: usize) -> String {
+    if offset > 0 && chunk.line(offset) == chunk.line(offset - 1) {
+        "   |".to_string()
+    } else {
+        format!("{:4}", chunk.line(offset))
+    }
+}
+
+// How the closure built by the OP_CLOSURE at `offset` captures each of its
+// upvalues; nothing for any other instruction.
+fn captures(chunk: &Chunk, offset: usize, op: OpCode, heap: &Heap) -> Vec<String> {
+    if op.short_form() != OpCode::Closure {
+        return Vec::new();
+    }
+    match chunk.constants[constant_index(chunk, offset, op)].unpack() {
+        Unpacked::Function(function) => heap[function]
+            .upvalues
+            .iter()
+            .map(|upvalue| {
+                let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                format!("{} {}", kind, upvalue.index)
+            })
+            .collect(),
+        _ => Vec::new(),
     }
 }
@@ -0,0 +1,221 @@
+// diagnostic.rs
+// Compile errors as data. The compiler collects these instead of printing,
+// so callers can either render them for a terminal or hand them to a tool.
+use std::fmt;
+
+use crate::scanner::Span;
+use crate::trace::json_string;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+        }
+    }
+}
+
+impl Severity {
+    // The lowercase name used in JSON output.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub span: Span,    // The offending source text; empty at the end of the input
+    pub line: usize,   // Line of `span.start`, from 1
+    pub column: usize, // Column of `span.start`, from 1
+    pub message: String,
+    pub note: Option<String>, // Extra context shown below the snippet
+}
+
+impl Diagnostic {
+    // Formats the diagnostic like clox (`[line 3] Error at 'x': message`),
+    // followed by the offending source line with the span underlined:
+    //
+    //   [line 3] Error at '=': Invalid assignment target.
+    //     |
+    //   3 | a + b = c;
+    //     |       ^
+    pub fn render(&self, source: &str) -> String {
+        let start = self.span.start.min(source.len());
+        let end = self.span.end.clamp(start, source.len());
+
+        let mut out = format!("[line {}] {}", self.line, self.severity);
+        if start == source.len() {
+            out.push_str(" at end");
+        } else {
+            out.push_str(&format!(" at '{}'", &source[start..end]));
+        }
+        out.push_str(&format!(": {}\n", self.message));
+
+        // The full source line containing the start of the span.
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        let text = &source[line_start..line_end];
+
+        // Keep tabs in the padding so the carets stay aligned with the text above.
+        let padding: String = source[line_start..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        // A span running onto later lines is only underlined up to the end of this one.
+        let width = source[start..end.min(line_end)].chars().count().max(1);
+
+        let gutter = " ".repeat(self.line.to_string().len());
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", self.line, text));
+        out.push_str(&format!("{} | {}{}", gutter, padding, "^".repeat(width)));
+        if let Some(note) = &self.note {
+            out.push_str(&format!("\n{} = note: {}", gutter, note));
+        }
+        out
+    }
+
+    // One JSON object on a single line, for editors and other tools:
+    //
+    //   {"severity":"error","line":3,"column":7,"span":{"start":21,"end":22},"message":"Invalid assignment target.","note":null}
+    //
+    // `column` counts characters from 1, while the span is in bytes, so a tool
+    // can slice the source it passed in without decoding it again.
+    pub fn to_json(&self) -> String {
+        let note = match &self.note {
+            Some(note) => json_string(note),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"severity\":\"{}\",\"line\":{},\"column\":{},\"span\":{{\"start\":{},\"end\":{}}},\"message\":{},\"note\":{}}}",
+            self.severity.name(),
+            self.line,
+            self.column,
+            self.span.start,
+            self.span.end,
+            json_string(&self.message),
+            note
+        )
+    }
+}
+
+// A one-line form that doesn't need the source, e.g. `[line 3:7] Error: message`.
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[line {}:{}] {}: {}",
+            self.line, self.column, self.severity, self.message
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str, start: usize, end: usize, message: &str) -> Diagnostic {
+        let before = &source[..start];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Diagnostic {
+            severity: Severity::Error,
+            span: Span { start, end },
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            message: message.to_string(),
+            note: None,
+        }
+    }
+
+    #[test]
+    fn underlines_multi_byte_tokens_by_character() {
+        // The string token is 6 characters but 10 bytes, and is preceded by a
+        // 2-byte character, so byte counts would misplace and overstretch the carets.
+        let source = "var é = \"日本ok\" + ;";
+        let start = source.find('"').unwrap();
+        let diagnostic = error(source, start, start + 10, "Operands must be numbers.");
+        assert_eq!(
+            diagnostic.render(source),
+            "[line 1] Error at '\"日本ok\"': Operands must be numbers.\n\
+             \x20 |\n\
+             1 | var é = \"日本ok\" + ;\n\
+             \x20 |         ^^^^^^"
+        );
+    }
+
+    #[test]
+    fn keeps_tabs_in_the_padding() {
+        let source = "\tprint\t= 1;";
+        let diagnostic = error(source, 7, 8, "Expect expression.");
+        assert!(
+            diagnostic
+                .render(source)
+                .ends_with("1 | \tprint\t= 1;\n  | \t     \t^")
+        );
+    }
+
+    #[test]
+    fn underlines_the_end_of_the_input() {
+        let source = "print 1";
+        let diagnostic = error(source, 7, 7, "Expect ';' after value.");
+        assert_eq!(
+            diagnostic.render(source),
+            "[line 1] Error at end: Expect ';' after value.\n\
+             \x20 |\n\
+             1 | print 1\n\
+             \x20 |        ^"
+        );
+    }
+
+    #[test]
+    fn underlines_a_token_at_the_end_of_a_line() {
+        let source = "var a = 1\nprint a;";
+        let diagnostic = error(source, 8, 9, "Expect ';' after variable declaration.");
+        assert_eq!(
+            diagnostic.render(source),
+            "[line 1] Error at '1': Expect ';' after variable declaration.\n\
+             \x20 |\n\
+             1 | var a = 1\n\
+             \x20 |         ^"
+        );
+    }
+
+    #[test]
+    fn stops_underlining_at_the_end_of_the_line() {
+        let source = "x = \"one\ntwo\";";
+        let mut diagnostic = error(source, 4, 13, "Can only call functions and classes.");
+        diagnostic.note = Some("strings may span lines".to_string());
+        assert_eq!(
+            diagnostic.render(source),
+            "[line 1] Error at '\"one\ntwo\"': Can only call functions and classes.\n\
+             \x20 |\n\
+             1 | x = \"one\n\
+             \x20 |     ^^^^\n\
+             \x20 = note: strings may span lines"
+        );
+    }
+
+    #[test]
+    fn json_has_the_position_and_escaped_text() {
+        let source = "a\n  é \"b\";";
+        let mut diagnostic = error(source, 7, 10, "Bad \"token\".");
+        assert_eq!(
+            diagnostic.to_json(),
+            r#"{"severity":"error","line":2,"column":5,"span":{"start":7,"end":10},"message":"Bad \"token\".","note":null}"#
+        );
+        diagnostic.note = Some("line one\nline two".to_string());
+        assert!(
+            diagnostic
+                .to_json()
+                .ends_with(r#""note":"line one\nline two"}"#)
+        );
+    }
+}
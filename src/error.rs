@@ -0,0 +1,62 @@
+// error.rs
+// Errors reported by `VM::interpret`.
+use std::fmt;
+
+use crate::diagnostic::Diagnostic;
+
+// One active call at the moment a runtime error happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    pub function: Option<String>, // None for the top-level script
+    pub line: usize,              // Line of the instruction the frame was executing
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {}()", self.line, name),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
+
+// An error raised while executing bytecode, with the call stack at that point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub line: usize,            // Line of the failing instruction
+    pub trace: Vec<TraceFrame>, // Innermost call first
+}
+
+// Prints like clox: the message, then one line per frame from the innermost call outwards.
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+#[derive(Debug)]
+pub enum InterpretError {
+    Compile(Vec<Diagnostic>),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterpretError::Compile(diagnostics) => {
+                let lines: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            InterpretError::Runtime(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for InterpretError {}
@@ -0,0 +1,798 @@
+// loxc.rs
+// Precompiled bytecode files. `--compile` writes a script's compiled functions
+// to a .loxc file, which can then be run without scanning or compiling again.
+//
+// Layout (all integers little-endian):
+//
+//   header:    magic "\x7fLOXC" | version u16 | checksum u32 (FNV-1a of the payload)
+//   payload:   function (the top-level script)
+//   function:  name | arity u32 | upvalue count u32, then (is_local u8, index u32) each
+//              | constant count u32, then constant each
+//              | code length u32, then the chunk's code bytes
+//              | line run count u32, then (offset u32, line u32) each
+//              | inline cache count u32 (the caches start out empty)
+//   name:      0 u8 for the script, or 1 u8 then string
+//   constant:  0 nil | 1 false | 2 true | 3 number (f64 bits u64) | 4 string | 5 function
+//   string:    byte length u32, then UTF-8 bytes
+//
+// Loading is done in three steps, so nothing reaches the heap or the VM before
+// the whole file has been checked: decode into plain `Prototype`s, verify them,
+// then allocate the functions.
+use crate::chunk::{Chunk, InlineCache, LineStart, OpCode};
+use crate::memory::{GcRef, Heap};
+use crate::object::{Function, UpvalueRef};
+use crate::table::hash_bytes;
+use crate::value::{Unpacked, Value};
+use crate::vm::VM;
+
+const MAGIC: &[u8] = b"\x7fLOXC";
+const FORMAT_VERSION: u16 = 6;
+const HEADER_LEN: usize = MAGIC.len() + 2 + 4;
+
+// Deeper nesting than this is rejected rather than risking the native stack.
+const MAX_NESTING: usize = 256;
+
+pub fn is_loxc(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/* ========== 写出 (Writing) ========== */
+
+// Serializes `function` and everything nested in it.
+pub fn write(function: GcRef<Function>, heap: &Heap) -> Vec<u8> {
+    let mut payload = Vec::new();
+    write_function(&mut payload, &heap[function], heap);
+
+    let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    out.extend_from_slice(&hash_bytes(&payload).to_le_bytes());
+    out.extend_from_slice(&payload);
+    out
+}
+
+fn write_function(out: &mut Vec<u8>, function: &Function, heap: &Heap) {
+    match function.name {
+        Some(name) => {
+            out.push(1);
+            write_string(out, &heap[name].chars);
+        }
+        None => out.push(0),
+    }
+    write_u32(out, function.arity);
+    write_u32(out, function.upvalues.len());
+    for upvalue in &function.upvalues {
+        out.push(upvalue.is_local as u8);
+        write_u32(out, upvalue.index);
+    }
+
+    let chunk = &function.chunk;
+    write_u32(out, chunk.constants.len());
+    for constant in &chunk.constants {
+        match constant.unpack() {
+            Unpacked::Nil => out.push(0),
+            Unpacked::Bool(false) => out.push(1),
+            Unpacked::Bool(true) => out.push(2),
+            Unpacked::Number(n) => {
+                out.push(3);
+                out.extend_from_slice(&n.to_bits().to_le_bytes());
+            }
+            Unpacked::String(string) => {
+                out.push(4);
+                write_string(out, &heap[string].chars);
+            }
+            Unpacked::Function(nested) => {
+                out.push(5);
+                write_function(out, &heap[nested], heap);
+            }
+            _ => unreachable!(
+                "the compiler never emits a {} constant",
+                constant.type_name()
+            ),
+        }
+    }
+
+    write_u32(out, chunk.code.len());
+    out.extend_from_slice(&chunk.code);
+    write_u32(out, chunk.lines.len());
+    for run in &chunk.lines {
+        write_u32(out, run.offset);
+        write_u32(out, run.line);
+    }
+    write_u32(out, chunk.caches.len());
+}
+
+fn write_u32(out: &mut Vec<u8>, n: usize) {
+    let n = u32::try_from(n).expect("value too large for a .loxc file");
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    write_u32(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+/* ========== 读取 (Reading) ========== */
+
+// A function decoded from a file but not yet on the heap.
+struct Prototype {
+    name: Option<String>,
+    arity: usize,
+    upvalues: Vec<UpvalueRef>,
+    constants: Vec<Constant>,
+    chunk: Chunk, // Code and lines; the constants are filled in once allocated
+}
+
+enum Constant {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Function(Box<Prototype>),
+}
+
+// Decodes and verifies a .loxc file, then allocates its functions.
+// Returns the top-level script function, ready for `VM::execute`.
+pub fn load(vm: &mut VM, bytes: &[u8]) -> Result<GcRef<Function>, String> {
+    if !is_loxc(bytes) {
+        return Err("Not a .loxc file.".to_string());
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err("Truncated header.".to_string());
+    }
+    let header = &bytes[MAGIC.len()..HEADER_LEN];
+    let version = u16::from_le_bytes([header[0], header[1]]);
+    if version != FORMAT_VERSION {
+        return Err(format!(
+            "Unsupported format version {} (expected {}).",
+            version, FORMAT_VERSION
+        ));
+    }
+    let checksum = u32::from_le_bytes([header[2], header[3], header[4], header[5]]);
+    let payload = &bytes[HEADER_LEN..];
+    if hash_bytes(payload) != checksum {
+        return Err("Checksum mismatch; the file is corrupt.".to_string());
+    }
+
+    let mut reader = Reader {
+        bytes: payload,
+        position: 0,
+    };
+    let script = reader.function(0)?;
+    if reader.position != payload.len() {
+        return Err(format!(
+            "Unexpected data after the script at byte {}.",
+            HEADER_LEN + reader.position
+        ));
+    }
+    if script.name.is_some() || script.arity != 0 || !script.upvalues.is_empty() {
+        return Err("Top-level function is not a script.".to_string());
+    }
+    verify(&script)?;
+    Ok(materialize(vm, script))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> Result<&[u8], String> {
+        if self.bytes.len() - self.position < count {
+            return Err(format!(
+                "Unexpected end of file at byte {}.",
+                HEADER_LEN + self.bytes.len()
+            ));
+        }
+        let bytes = &self.bytes[self.position..self.position + count];
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        let mut bits = [0; 8];
+        bits.copy_from_slice(self.take(8)?);
+        Ok(f64::from_bits(u64::from_le_bytes(bits)))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let length = self.u32()?;
+        let start = HEADER_LEN + self.position;
+        let bytes = self.take(length)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| format!("Invalid UTF-8 in string at byte {}.", start))
+    }
+
+    fn function(&mut self, depth: usize) -> Result<Prototype, String> {
+        if depth > MAX_NESTING {
+            return Err("Functions are nested too deeply.".to_string());
+        }
+        let name = match self.u8()? {
+            0 => None,
+            1 => Some(self.string()?),
+            tag => return Err(format!("Invalid function name tag {}.", tag)),
+        };
+        let arity = self.u32()?;
+
+        let upvalue_count = self.u32()?;
+        let mut upvalues = Vec::new();
+        for _ in 0..upvalue_count {
+            let is_local = match self.u8()? {
+                0 => false,
+                1 => true,
+                tag => return Err(format!("Invalid upvalue tag {}.", tag)),
+            };
+            let index = self.u32()?;
+            upvalues.push(UpvalueRef { is_local, index });
+        }
+
+        // Counts come from the file, so vectors grow as items are actually read
+        // instead of trusting them for an up-front allocation.
+        let constant_count = self.u32()?;
+        let mut constants = Vec::new();
+        for _ in 0..constant_count {
+            let constant = match self.u8()? {
+                0 => Constant::Nil,
+                1 => Constant::Bool(false),
+                2 => Constant::Bool(true),
+                3 => Constant::Number(self.f64()?),
+                4 => Constant::String(self.string()?),
+                5 => Constant::Function(Box::new(self.function(depth + 1)?)),
+                tag => return Err(format!("Invalid constant tag {}.", tag)),
+            };
+            constants.push(constant);
+        }
+
+        let code_length = self.u32()?;
+        let code = self.take(code_length)?.to_vec();
+        let run_count = self.u32()?;
+        let mut lines = Vec::new();
+        for _ in 0..run_count {
+            let offset = self.u32()?;
+            let line = self.u32()?;
+            lines.push(LineStart { offset, line });
+        }
+        // Every cache belongs to a four-byte instruction, which bounds the count
+        // before anything is allocated for it.
+        let cache_count = self.u32()?;
+        if cache_count > code.len() / 4 {
+            return Err(format!("Invalid inline cache count {}.", cache_count));
+        }
+
+        Ok(Prototype {
+            name,
+            arity,
+            upvalues,
+            constants,
+            chunk: Chunk {
+                code,
+                constants: Vec::new(),
+                lines,
+                caches: vec![InlineCache::default(); cache_count],
+                ..Chunk::new()
+            },
+        })
+    }
+}
+
+/* ========== 校验 (Verification) ========== */
+
+// Checks that running the function can't make the VM index out of bounds or
+// misread a constant: every operand refers to something that exists, every
+// jump lands on an instruction, the stack never underflows, every path ends in
+// OP_RETURN, and the stack has the same height whichever way an instruction is
+// reached. Code that can't be reached is never run, so it isn't checked.
+fn verify(function: &Prototype) -> Result<(), String> {
+    let name = function.name.as_deref().unwrap_or("script");
+    verify_code(function).map_err(|message| format!("In {}: {}", name, message))?;
+    for constant in &function.constants {
+        if let Constant::Function(nested) = constant {
+            verify(nested)?;
+        }
+    }
+    Ok(())
+}
+
+fn verify_code(function: &Prototype) -> Result<(), String> {
+    let chunk = &function.chunk;
+    let code = &chunk.code;
+
+    // Every byte needs a line (for runtime errors), so the runs must start at
+    // offset 0 and go strictly forward.
+    let runs_valid = chunk
+        .lines
+        .first()
+        .map_or(code.is_empty(), |run| run.offset == 0)
+        && chunk
+            .lines
+            .windows(2)
+            .all(|runs| runs[0].offset < runs[1].offset)
+        && chunk.lines.last().is_none_or(|run| run.offset < code.len());
+    if !runs_valid {
+        return Err("Invalid line table.".to_string());
+    }
+
+    // Decode the code front to back: every opcode must be known and the last
+    // instruction's operands must fit, so the instruction boundaries are certain.
+    let mut starts = vec![false; code.len()];
+    for offset in chunk.offsets() {
+        let Some(op) = chunk.op(offset) else {
+            return Err(format!("Unknown opcode {} at {:04}.", code[offset], offset));
+        };
+        if offset + op.size() > code.len() {
+            return Err(format!("Truncated instruction at {:04}.", offset));
+        }
+        starts[offset] = true;
+    }
+
+    // Stack height (relative to the frame's first slot) before each instruction.
+    let mut heights: Vec<Option<usize>> = vec![None; code.len()];
+    // Slot 0 holds the callee, followed by the parameters.
+    let mut pending = vec![(0, function.arity + 1)];
+
+    while let Some((offset, height)) = pending.pop() {
+        if offset >= code.len() {
+            return Err("Execution can run past the end of the code.".to_string());
+        }
+        if !starts[offset] {
+            return Err(format!(
+                "Jump into the middle of an instruction at {:04}.",
+                offset
+            ));
+        }
+        match heights[offset] {
+            Some(known) if known == height => continue,
+            Some(known) => {
+                return Err(format!(
+                    "Stack height at {:04} is both {} and {}.",
+                    offset, known, height
+                ));
+            }
+            None => heights[offset] = Some(height),
+        }
+        let error = |message: String| format!("{} at {:04}.", message, offset);
+
+        let op = chunk.op(offset).expect("opcodes checked above");
+        check_operands(function, op, offset, height).map_err(error)?;
+        let (needs, pops, pushes) = stack_effect(chunk, op, offset);
+        if height < needs {
+            return Err(error("Stack underflow".to_string()));
+        }
+        let next_height = height - pops + pushes;
+        let next = offset + op.size();
+
+        match op {
+            OpCode::Return => {}
+            OpCode::Jump => pending.push((next + chunk.read_u16(offset + 1), next_height)),
+            OpCode::JumpIfFalse
+            | OpCode::JumpIfNotLess
+            | OpCode::JumpIfNotGreater
+            | OpCode::JumpIfNotEqual => {
+                pending.push((next + chunk.read_u16(offset + 1), next_height));
+                pending.push((next, next_height));
+            }
+            OpCode::Loop => match next.checked_sub(chunk.read_u16(offset + 1)) {
+                Some(target) => pending.push((target, next_height)),
+                None => return Err(error("Loop jumps before the start".to_string())),
+            },
+            _ => pending.push((next, next_height)),
+        }
+    }
+    Ok(())
+}
+
+fn check_operands(
+    function: &Prototype,
+    op: OpCode,
+    offset: usize,
+    height: usize,
+) -> Result<(), String> {
+    let code = &function.chunk.code;
+    let constant = |index: usize| {
+        function
+            .constants
+            .get(index)
+            .ok_or(format!("Constant {} out of range", index))
+    };
+    let value = |index: usize| match constant(index)? {
+        Constant::Function(_) => Err(format!("Constant {} is a bare function", index)),
+        _ => Ok(()),
+    };
+    let name = |index: usize| match constant(index)? {
+        Constant::String(_) => Ok(()),
+        _ => Err(format!("Constant {} is not a name", index)),
+    };
+    // The first operand; in a long form it is a three-byte constant index.
+    let operand = if op.is_long() {
+        function.chunk.read_u24(offset + 1)
+    } else {
+        code.get(offset + 1).map_or(0, |&byte| byte as usize)
+    };
+    match op.short_form() {
+        OpCode::Constant | OpCode::AddConstant | OpCode::SubtractConstant => value(operand),
+        OpCode::DefineGlobal
+        | OpCode::GetGlobal
+        | OpCode::SetGlobal
+        | OpCode::Class
+        | OpCode::Method
+        | OpCode::GetSuper
+        | OpCode::SuperInvoke => name(operand),
+        OpCode::GetProperty | OpCode::SetProperty | OpCode::Invoke => {
+            // The cache index is the last two bytes of the instruction.
+            let cache = function.chunk.read_u16(offset + op.size() - 2);
+            if cache >= function.chunk.caches.len() {
+                return Err(format!("Inline cache {} out of range", cache));
+            }
+            name(operand)
+        }
+        OpCode::GetLocal | OpCode::SetLocal if operand >= height => {
+            Err(format!("Local slot {} is not on the stack", operand))
+        }
+        OpCode::GetLocalAddConstant | OpCode::GetLocalSubtractConstant => {
+            if operand >= height {
+                return Err(format!("Local slot {} is not on the stack", operand));
+            }
+            value(code.get(offset + 2).map_or(0, |&byte| byte as usize))
+        }
+        OpCode::GetUpvalue | OpCode::SetUpvalue if operand >= function.upvalues.len() => {
+            Err(format!("Upvalue {} out of range", operand))
+        }
+        OpCode::Closure => {
+            let Constant::Function(nested) = constant(operand)? else {
+                return Err(format!("Constant {} is not a function", operand));
+            };
+            // The new closure captures from this frame's slots or this closure's upvalues.
+            for upvalue in &nested.upvalues {
+                let limit = if upvalue.is_local {
+                    height
+                } else {
+                    function.upvalues.len()
+                };
+                if upvalue.index >= limit {
+                    return Err(format!("Captured variable {} out of range", upvalue.index));
+                }
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+// How many values an instruction needs on the stack, how many it pops, and how many it pushes.
+fn stack_effect(chunk: &Chunk, op: OpCode, offset: usize) -> (usize, usize, usize) {
+    match op {
+        OpCode::Constant
+        | OpCode::ConstantLong
+        | OpCode::Nil
+        | OpCode::True
+        | OpCode::False
+        | OpCode::GetGlobal
+        | OpCode::GetGlobalLong
+        | OpCode::GetLocal
+        | OpCode::GetUpvalue
+        | OpCode::Closure
+        | OpCode::ClosureLong
+        | OpCode::Class
+        | OpCode::ClassLong
+        | OpCode::GetLocalAddConstant
+        | OpCode::GetLocalSubtractConstant => (0, 0, 1),
+        OpCode::Negate
+        | OpCode::Not
+        | OpCode::GetProperty
+        | OpCode::GetPropertyLong
+        | OpCode::AddConstant
+        | OpCode::SubtractConstant => (1, 1, 1),
+        OpCode::SetGlobal | OpCode::SetGlobalLong | OpCode::SetLocal | OpCode::SetUpvalue => {
+            (1, 0, 0)
+        }
+        OpCode::JumpIfFalse => (1, 0, 0),
+        OpCode::Jump | OpCode::Loop => (0, 0, 0),
+        OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::Equal
+        | OpCode::Greater
+        | OpCode::Less => (2, 2, 1),
+        OpCode::JumpIfNotLess | OpCode::JumpIfNotGreater | OpCode::JumpIfNotEqual => (2, 2, 0),
+        OpCode::PopN => {
+            let count = chunk.code[offset + 1] as usize;
+            (count, count, 0)
+        }
+        OpCode::Print
+        | OpCode::Pop
+        | OpCode::DefineGlobal
+        | OpCode::DefineGlobalLong
+        | OpCode::CloseUpvalue => (1, 1, 0),
+        OpCode::Return => (1, 1, 0),
+        OpCode::SetProperty | OpCode::SetPropertyLong => (2, 2, 1),
+        OpCode::Method | OpCode::MethodLong | OpCode::Inherit => (2, 1, 0),
+        OpCode::GetSuper | OpCode::GetSuperLong => (2, 2, 1),
+        // The callee and its arguments are replaced by the result.
+        OpCode::Call => {
+            let arg_count = chunk.code[offset + 1] as usize;
+            (arg_count + 1, arg_count + 1, 1)
+        }
+        // The receiver is replaced by the result, along with the arguments.
+        OpCode::Invoke | OpCode::InvokeLong => {
+            let arg_count = chunk.code[offset + op.size() - 3] as usize;
+            (arg_count + 1, arg_count + 1, 1)
+        }
+        // The receiver and arguments, then the superclass on top.
+        OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+            let arg_count = chunk.code[offset + op.size() - 1] as usize;
+            (arg_count + 2, arg_count + 2, 1)
+        }
+    }
+}
+
+/* ========== 分配 (Allocation) ========== */
+
+// Moves a verified prototype onto the heap. Everything allocated for a function
+// stays on the VM stack until the function itself exists and holds on to it.
+fn materialize(vm: &mut VM, prototype: Prototype) -> GcRef<Function> {
+    let mut roots = 0;
+    let mut constants = Vec::with_capacity(prototype.constants.len());
+    for constant in prototype.constants {
+        let value = match constant {
+            Constant::Nil => Value::NIL,
+            Constant::Bool(b) => Value::bool(b),
+            Constant::Number(n) => Value::number(n),
+            Constant::String(s) => Value::from(vm.intern(s)),
+            Constant::Function(nested) => Value::from(materialize(vm, *nested)),
+        };
+        vm.push_root(value);
+        roots += 1;
+        constants.push(value);
+    }
+    let name = prototype.name.map(|name| {
+        let name = vm.intern(name);
+        vm.push_root(Value::from(name));
+        roots += 1;
+        name
+    });
+
+    let mut chunk = prototype.chunk;
+    chunk.constants = constants;
+    let function = vm.alloc(Function {
+        arity: prototype.arity,
+        chunk,
+        name,
+        upvalues: prototype.upvalues,
+    });
+    for _ in 0..roots {
+        vm.pop_root();
+    }
+    function
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A function in the file format, with every byte on line 1.
+    fn function(
+        name: Option<&str>,
+        upvalues: &[(bool, usize)],
+        constants: &[Vec<u8>],
+        code: &[u8],
+        caches: usize,
+    ) -> Vec<u8> {
+        let mut out = Vec::new();
+        match name {
+            Some(name) => {
+                out.push(1);
+                write_string(&mut out, name);
+            }
+            None => out.push(0),
+        }
+        write_u32(&mut out, 0);
+        write_u32(&mut out, upvalues.len());
+        for &(is_local, index) in upvalues {
+            out.push(is_local as u8);
+            write_u32(&mut out, index);
+        }
+        write_u32(&mut out, constants.len());
+        for constant in constants {
+            out.extend_from_slice(constant);
+        }
+        write_u32(&mut out, code.len());
+        out.extend_from_slice(code);
+        write_u32(&mut out, 1);
+        write_u32(&mut out, 0);
+        write_u32(&mut out, 1);
+        write_u32(&mut out, caches);
+        out
+    }
+
+    fn script(constants: &[Vec<u8>], code: &[u8]) -> Vec<u8> {
+        function(None, &[], constants, code, 0)
+    }
+
+    fn number(n: f64) -> Vec<u8> {
+        let mut out = vec![3];
+        out.extend_from_slice(&n.to_bits().to_le_bytes());
+        out
+    }
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut out = vec![4];
+        write_string(&mut out, s);
+        out
+    }
+
+    fn nested(function: Vec<u8>) -> Vec<u8> {
+        let mut out = vec![5];
+        out.extend(function);
+        out
+    }
+
+    // The header, with a correct checksum, then `payload`.
+    fn file(payload: &[u8]) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        out.extend_from_slice(&hash_bytes(payload).to_le_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    fn load_error(bytes: &[u8]) -> String {
+        match load(&mut VM::new(), bytes) {
+            Ok(_) => panic!("file was accepted"),
+            Err(message) => message,
+        }
+    }
+
+    #[track_caller]
+    fn assert_rejected(bytes: &[u8], expected: &str) {
+        let message = load_error(bytes);
+        assert!(
+            message.contains(expected),
+            "{message:?} should mention {expected:?}"
+        );
+    }
+
+    const NIL: u8 = OpCode::Nil as u8;
+    const POP: u8 = OpCode::Pop as u8;
+    const RETURN: u8 = OpCode::Return as u8;
+
+    #[test]
+    fn accepts_a_valid_file() {
+        let payload = script(&[number(1.0)], &[OpCode::Constant as u8, 0, RETURN]);
+        assert!(load(&mut VM::new(), &file(&payload)).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_bad_header() {
+        let good = file(&script(&[], &[NIL, RETURN]));
+
+        let mut bad_magic = good.clone();
+        bad_magic[1] = b'X';
+        assert_rejected(&bad_magic, "Not a .loxc file.");
+
+        let mut bad_version = good.clone();
+        bad_version[MAGIC.len()..MAGIC.len() + 2]
+            .copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert_rejected(&bad_version, "Unsupported format version");
+
+        let mut bad_checksum = good.clone();
+        *bad_checksum.last_mut().unwrap() ^= 1;
+        assert_rejected(&bad_checksum, "Checksum mismatch");
+
+        assert_rejected(&good[..HEADER_LEN - 1], "Truncated header.");
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let payload = script(&[string("a long enough string")], &[NIL, RETURN]);
+        // Cut anywhere, with a checksum that matches what's left.
+        for length in 0..payload.len() {
+            assert_rejected(&file(&payload[..length]), "Unexpected end of file");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_opcodes() {
+        assert_rejected(
+            &file(&script(&[], &[NIL, 255])),
+            "Unknown opcode 255 at 0001.",
+        );
+        assert_rejected(
+            &file(&script(&[], &[OpCode::Constant as u8])),
+            "Truncated instruction at 0000.",
+        );
+    }
+
+    #[test]
+    fn rejects_operands_out_of_range() {
+        let constant = script(&[number(1.0)], &[OpCode::Constant as u8, 1, RETURN]);
+        assert_rejected(&file(&constant), "Constant 1 out of range at 0000.");
+
+        let name = script(&[number(1.0)], &[OpCode::GetGlobal as u8, 0, RETURN]);
+        assert_rejected(&file(&name), "Constant 0 is not a name");
+
+        let local = script(&[], &[OpCode::GetLocal as u8, 1, RETURN]);
+        assert_rejected(&file(&local), "Local slot 1 is not on the stack at 0000.");
+
+        let upvalue = script(&[], &[OpCode::GetUpvalue as u8, 0, RETURN]);
+        assert_rejected(&file(&upvalue), "Upvalue 0 out of range at 0000.");
+
+        let code = [NIL, OpCode::GetProperty as u8, 0, 0, 0, RETURN];
+        assert_rejected(
+            &file(&script(&[string("x")], &code)),
+            "Inline cache 0 out of range at 0001.",
+        );
+
+        // The closure would capture slot 1, but only slot 0 exists.
+        let inner = function(Some("inner"), &[(true, 1)], &[], &[NIL, RETURN], 0);
+        let closure = script(&[nested(inner)], &[OpCode::Closure as u8, 0, RETURN]);
+        assert_rejected(&file(&closure), "Captured variable 1 out of range at 0000.");
+    }
+
+    #[test]
+    fn rejects_a_jump_into_an_instruction() {
+        // The jump lands on the Constant's operand byte.
+        let code = [OpCode::Jump as u8, 0, 1, OpCode::Constant as u8, 0, RETURN];
+        assert_rejected(
+            &file(&script(&[number(1.0)], &code)),
+            "Jump into the middle of an instruction at 0004.",
+        );
+
+        let code = [OpCode::Loop as u8, 0, 9, RETURN];
+        assert_rejected(
+            &file(&script(&[], &code)),
+            "Loop jumps before the start at 0000.",
+        );
+
+        assert_rejected(
+            &file(&script(&[], &[NIL, POP])),
+            "Execution can run past the end of the code.",
+        );
+    }
+
+    #[test]
+    fn rejects_inconsistent_stack_heights() {
+        // Falling through pushes one more value than jumping does.
+        let code = [
+            OpCode::True as u8,
+            OpCode::JumpIfFalse as u8,
+            0,
+            1,
+            NIL,
+            RETURN,
+        ];
+        assert_rejected(&file(&script(&[], &code)), "Stack height at 0005 is both");
+
+        // Only the callee slot is on the stack.
+        assert_rejected(
+            &file(&script(&[], &[POP, POP, NIL, RETURN])),
+            "Stack underflow at 0001.",
+        );
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let nest = |depth: usize| {
+            let mut payload = function(Some("f"), &[], &[], &[NIL, RETURN], 0);
+            for _ in 0..depth {
+                payload = function(Some("f"), &[], &[nested(payload)], &[NIL, RETURN], 0);
+            }
+            script(&[nested(payload)], &[NIL, RETURN])
+        };
+        assert!(load(&mut VM::new(), &file(&nest(MAX_NESTING - 1))).is_ok());
+        assert_rejected(
+            &file(&nest(MAX_NESTING)),
+            "Functions are nested too deeply.",
+        );
+    }
+}
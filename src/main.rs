@@ -1,44 +1,329 @@
-use chunk::OpCode;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use error::InterpretError;
+use memory::GcRef;
+use object::Function;
+use trace::{FunctionFilter, JsonTracer, TextTracer, Tracer};
 
 mod chunk;
 mod compiler;
 mod debug;
+mod diagnostic;
+mod error;
+mod loxc;
+mod memory;
+mod natives;
+mod object;
+mod optimize;
 mod scanner;
+mod table;
 mod token_type;
+mod trace;
 mod value;
 mod vm;
-fn main() {
-    // let args = std::env::args().collect::<Vec<String>>();
-
-    // match args.len() {
-    //     2 => {
-    //         // let script = &args[1];
-    //         let script = r"./test.lox";
-    //         let _ = run_file(script);
-    //     }
-    //     _ => {
-    //         eprintln!("Usage: {} <script>", args[0]);
-    //         std::process::exit(1);
-    //     }
-    // }
-    let script = r"./src/test.lox";
-    let _ = run_file(script);
-}
-fn run_file(script: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let source = std::fs::read_to_string(script);
-    match source {
-        Ok(content) => {
-            let mut vm = vm::VM::new();
-            let c = vm.interpret(content);
-            match c {
-                Ok(_) => println!("Script executed successfully."),
-                Err(e) => eprintln!("Error executing script: {}", e),
+
+// Exit codes from BSD's sysexits.h, as used by clox.
+const EX_USAGE: u8 = 64; // Bad command line
+const EX_DATAERR: u8 = 65; // Compile error in the script
+const EX_SOFTWARE: u8 = 70; // Runtime error in the script
+const EX_CANTCREAT: u8 = 73; // Output file couldn't be written
+const EX_IOERR: u8 = 74; // Script couldn't be read
+
+const USAGE: &str = "Usage: clox-rs [options] [script [args...]]
+
+Options:
+  --compile                Compile the script to a .loxc file instead of
+                           running it; .loxc files run like scripts
+  --output=PATH            Write the compiled file to PATH (implies --compile)
+  --disassemble            Compile the script and print its bytecode instead
+                           of running it
+  -O0                      Don't optimize the bytecode (folding constants,
+                           dropping dead code), to debug the compiler
+  --diagnostics=FORMAT     Report compile errors as \"text\" (the default) or
+                           \"json\", one object per line with the severity,
+                           line, column, byte span and message
+  --stats                  Print VM statistics (inline cache hit rate) to
+                           stderr when the script ends
+  --trace                  Trace every executed instruction to stderr
+  --trace-json             Trace as one JSON object per instruction
+  --trace-file=PATH        Write the trace to PATH instead of stderr
+  --trace-function=NAME    Only trace code in function NAME (repeatable;
+                           \"script\" is top-level code)";
+
+// How compile errors are printed to stderr.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum DiagnosticFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+// Command-line options. Options must come before the script; everything
+// after the script is passed to it.
+#[derive(Default)]
+struct Options {
+    compile: bool,
+    output: Option<String>,
+    disassemble: bool,
+    no_optimize: bool,
+    diagnostics: DiagnosticFormat,
+    stats: bool,
+    trace: bool,
+    trace_json: bool,
+    trace_file: Option<String>,
+    trace_functions: Vec<String>,
+    script: Option<String>,
+    script_args: Vec<String>,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.iter();
+    for arg in args.by_ref() {
+        if !arg.starts_with('-') {
+            options.script = Some(arg.clone());
+            break;
+        }
+        // Any of the --trace-* options implies --trace.
+        if arg == "--compile" {
+            options.compile = true;
+        } else if let Some(path) = arg.strip_prefix("--output=") {
+            options.compile = true;
+            options.output = Some(path.to_string());
+        } else if arg == "--disassemble" {
+            options.disassemble = true;
+        } else if arg == "-O0" {
+            options.no_optimize = true;
+        } else if let Some(format) = arg.strip_prefix("--diagnostics=") {
+            options.diagnostics = match format {
+                "text" => DiagnosticFormat::Text,
+                "json" => DiagnosticFormat::Json,
+                _ => return Err(format!("Unknown diagnostics format '{}'.", format)),
+            };
+        } else if arg == "--stats" {
+            options.stats = true;
+        } else if arg == "--trace" {
+            options.trace = true;
+        } else if arg == "--trace-json" {
+            options.trace = true;
+            options.trace_json = true;
+        } else if let Some(path) = arg.strip_prefix("--trace-file=") {
+            options.trace = true;
+            options.trace_file = Some(path.to_string());
+        } else if let Some(name) = arg.strip_prefix("--trace-function=") {
+            options.trace = true;
+            options.trace_functions.push(name.to_string());
+        } else {
+            return Err(format!("Unknown option '{}'.", arg));
+        }
+    }
+    options.script_args = args.cloned().collect();
+    if options.compile && options.disassemble {
+        return Err("--compile and --disassemble can't be combined.".to_string());
+    }
+    if options.compile && options.script.is_none() {
+        return Err("--compile needs a script.".to_string());
+    }
+    if options.disassemble && options.script.is_none() {
+        return Err("--disassemble needs a script.".to_string());
+    }
+    Ok(options)
+}
+
+// With no script, starts a REPL. Arguments after the script can be read by it
+// with the `args()` and `arg(i)` built-ins.
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match parse_options(&args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            return ExitCode::from(EX_USAGE);
+        }
+    };
+
+    let mut vm = vm::VM::new();
+    natives::define_script_args(&mut vm, options.script_args.clone());
+    vm.optimize = !options.no_optimize;
+    if options.trace {
+        match make_tracer(&options) {
+            Ok(tracer) => vm.set_tracer(tracer),
+            Err(e) => {
+                eprintln!("Could not open trace file: {}.", e);
+                return ExitCode::from(EX_IOERR);
             }
-            Ok(())
         }
+    }
+
+    let code = match &options.script {
+        None => repl(&mut vm, options.diagnostics),
+        Some(script) if options.compile => compile_file(
+            &mut vm,
+            script,
+            options.output.as_deref(),
+            options.diagnostics,
+        ),
+        Some(script) if options.disassemble => {
+            disassemble_file(&mut vm, script, options.diagnostics)
+        }
+        Some(script) => run_file(&mut vm, script, options.diagnostics),
+    };
+    if options.stats {
+        print_stats(&vm.stats());
+    }
+    code
+}
+
+fn print_stats(stats: &vm::VmStats) {
+    eprintln!(
+        "inline caches: {} hits, {} misses, {} megamorphic ({:.1}% hit rate)",
+        stats.cache_hits,
+        stats.cache_misses,
+        stats.megamorphic_lookups,
+        stats.cache_hit_rate() * 100.0
+    );
+}
+
+fn make_tracer(options: &Options) -> io::Result<Box<dyn Tracer>> {
+    let out: Box<dyn Write> = match &options.trace_file {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stderr()),
+    };
+    let tracer: Box<dyn Tracer> = if options.trace_json {
+        Box::new(JsonTracer::new(out))
+    } else {
+        Box::new(TextTracer::new(out))
+    };
+    if options.trace_functions.is_empty() {
+        Ok(tracer)
+    } else {
+        let functions = options.trace_functions.clone();
+        Ok(Box::new(FunctionFilter::new(functions, tracer)))
+    }
+}
+
+// Reads the script and compiles it, or loads it if it was precompiled with
+// --compile. Errors are reported here; the exit code is returned.
+fn load_script(
+    vm: &mut vm::VM,
+    script: &str,
+    format: DiagnosticFormat,
+) -> Result<GcRef<Function>, ExitCode> {
+    let bytes = std::fs::read(script).map_err(|e| {
+        eprintln!("Could not read file \"{}\": {}.", script, e);
+        ExitCode::from(EX_IOERR)
+    })?;
+    if loxc::is_loxc(&bytes) {
+        return loxc::load(vm, &bytes).map_err(|message| {
+            eprintln!("Invalid bytecode file \"{}\": {}", script, message);
+            ExitCode::from(EX_DATAERR)
+        });
+    }
+    let source = String::from_utf8(bytes).map_err(|_| {
+        eprintln!("Could not read file \"{}\": not valid UTF-8.", script);
+        ExitCode::from(EX_DATAERR)
+    })?;
+    vm.compile(&source).map_err(|diagnostics| {
+        report(&InterpretError::Compile(diagnostics), &source, format);
+        ExitCode::from(EX_DATAERR)
+    })
+}
+
+fn run_file(vm: &mut vm::VM, script: &str, format: DiagnosticFormat) -> ExitCode {
+    let function = match load_script(vm, script, format) {
+        Ok(function) => function,
+        Err(code) => return code,
+    };
+    match vm.execute(function) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{}", error);
+            ExitCode::from(EX_SOFTWARE)
+        }
+    }
+}
+
+// Prints the compiled bytecode of every function in the script, in the
+// listing format documented in debug.rs.
+fn disassemble_file(vm: &mut vm::VM, script: &str, format: DiagnosticFormat) -> ExitCode {
+    match load_script(vm, script, format) {
+        Ok(function) => {
+            print!("{}", debug::disassemble_program(function, &vm.heap));
+            ExitCode::SUCCESS
+        }
+        Err(code) => code,
+    }
+}
+
+// Writes the compiled script to `output`, by default the script's path with a
+// .loxc extension.
+fn compile_file(
+    vm: &mut vm::VM,
+    script: &str,
+    output: Option<&str>,
+    format: DiagnosticFormat,
+) -> ExitCode {
+    let function = match load_script(vm, script, format) {
+        Ok(function) => function,
+        Err(code) => return code,
+    };
+    let output = match output {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(script).with_extension("loxc"),
+    };
+    match std::fs::write(&output, loxc::write(function, &vm.heap)) {
+        Ok(()) => ExitCode::SUCCESS,
         Err(e) => {
-            eprintln!("Error reading file {}: {}", script, e);
-            return Err(Box::new(e));
+            eprintln!("Could not write \"{}\": {}.", output.display(), e);
+            ExitCode::from(EX_CANTCREAT)
+        }
+    }
+}
+
+// Reads and runs one line at a time. The VM lives across lines, so globals
+// declared on one line are visible on the next; errors are reported and skipped.
+fn repl(vm: &mut vm::VM, format: DiagnosticFormat) -> ExitCode {
+    let stdin = io::stdin();
+    let mut line = String::new();
+    loop {
+        print!("> ");
+        if io::stdout().flush().is_err() {
+            return ExitCode::from(EX_IOERR);
+        }
+        line.clear();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => {
+                // End of input (Ctrl-D): finish the prompt line and leave.
+                println!();
+                return ExitCode::SUCCESS;
+            }
+            Ok(_) => {
+                if let Err(error) = vm.interpret(&line) {
+                    report(&error, &line, format);
+                }
+            }
+            Err(e) => {
+                eprintln!("Could not read input: {}.", e);
+                return ExitCode::from(EX_IOERR);
+            }
+        }
+    }
+}
+
+// Runtime errors are always text; only compile errors have a JSON form.
+fn report(error: &InterpretError, source: &str, format: DiagnosticFormat) {
+    match error {
+        InterpretError::Compile(diagnostics) => {
+            for diagnostic in diagnostics {
+                match format {
+                    DiagnosticFormat::Text => eprintln!("{}", diagnostic.render(source)),
+                    DiagnosticFormat::Json => eprintln!("{}", diagnostic.to_json()),
+                }
+            }
         }
+        InterpretError::Runtime(e) => eprintln!("{}", e),
     }
 }
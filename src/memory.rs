@@ -0,0 +1,392 @@
+// memory.rs
+// The garbage-collected heap. Every Lox object lives here and is referred to
+// through a `GcRef` handle. Reclamation is a tracing mark-sweep: whoever owns
+// the roots (the VM, and the compiler while it runs) marks them, then the heap
+// traces everything reachable from them and frees the rest.
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::mem;
+
+use crate::chunk::{ConstantKey, InlineCache, LineStart};
+use crate::object::{
+    BoundMethod, Class, Closure, Function, Instance, LoxString, Native, ParamType, Upvalue,
+};
+use crate::value::{Unpacked, Value};
+
+// Collect once this many bytes are live, then scale the threshold with the survivors.
+const INITIAL_GC_THRESHOLD: usize = 1024 * 1024;
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+// A handle to an object of type `T` stored in the `Heap`.
+// Handles are plain indices, so they are `Copy` and compare by identity.
+pub struct GcRef<T> {
+    index: usize,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> GcRef<T> {
+    fn new(index: usize) -> Self {
+        GcRef {
+            index,
+            _marker: PhantomData,
+        }
+    }
+}
+
+// A NaN-boxed value keeps only the index and rebuilds the handle from it.
+#[cfg(feature = "nan_boxing")]
+impl<T> GcRef<T> {
+    pub fn index(self) -> usize {
+        self.index
+    }
+
+    pub fn from_index(index: usize) -> Self {
+        GcRef::new(index)
+    }
+}
+
+// Implemented by hand: deriving would needlessly require `T` itself to be Copy/Eq/...
+impl<T> Clone for GcRef<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> Copy for GcRef<T> {}
+impl<T> PartialEq for GcRef<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+    }
+}
+impl<T> Eq for GcRef<T> {}
+impl<T> Hash for GcRef<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+    }
+}
+impl<T> fmt::Debug for GcRef<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "GcRef({})", self.index)
+    }
+}
+
+// Every kind of object the heap can hold.
+pub enum Obj {
+    String(LoxString),
+    Function(Function),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    Native(Native),
+}
+
+// Conversion between a concrete object type and the `Obj` stored in the heap,
+// which lets `GcRef<T>` hand out `&T` directly.
+pub trait HeapObject: Sized {
+    fn into_obj(self) -> Obj;
+    fn from_obj(obj: &Obj) -> &Self;
+    fn from_obj_mut(obj: &mut Obj) -> &mut Self;
+}
+
+macro_rules! heap_object {
+    ($variant:ident, $ty:ident) => {
+        impl HeapObject for $ty {
+            fn into_obj(self) -> Obj {
+                Obj::$variant(self)
+            }
+            fn from_obj(obj: &Obj) -> &Self {
+                match obj {
+                    Obj::$variant(inner) => inner,
+                    _ => unreachable!(concat!("heap object is not a ", stringify!($ty))),
+                }
+            }
+            fn from_obj_mut(obj: &mut Obj) -> &mut Self {
+                match obj {
+                    Obj::$variant(inner) => inner,
+                    _ => unreachable!(concat!("heap object is not a ", stringify!($ty))),
+                }
+            }
+        }
+    };
+}
+
+heap_object!(String, LoxString);
+heap_object!(Function, Function);
+heap_object!(Closure, Closure);
+heap_object!(Upvalue, Upvalue);
+heap_object!(Class, Class);
+heap_object!(Instance, Instance);
+heap_object!(BoundMethod, BoundMethod);
+heap_object!(Native, Native);
+
+impl Obj {
+    // Approximate number of bytes owned by this object, used to pace collections.
+    fn size(&self) -> usize {
+        let owned = match self {
+            Obj::String(string) => string.chars.len(),
+            Obj::Function(function) => {
+                function.chunk.code.len()
+                    + function.chunk.constants.len() * mem::size_of::<Value>()
+                    + function.chunk.lines.len() * mem::size_of::<LineStart>()
+                    + function.chunk.caches.len() * mem::size_of::<InlineCache>()
+                    + function.chunk.constant_indexes.len() * mem::size_of::<(ConstantKey, usize)>()
+            }
+            Obj::Closure(closure) => closure.upvalues.len() * mem::size_of::<GcRef<Upvalue>>(),
+            Obj::Upvalue(_) => 0,
+            Obj::Class(class) => class.methods.size(),
+            Obj::Instance(instance) => instance.fields.size(),
+            Obj::BoundMethod(_) => 0,
+            Obj::Native(native) => native.params.len() * mem::size_of::<ParamType>(),
+        };
+        mem::size_of::<HeapEntry>() + owned
+    }
+}
+
+struct HeapEntry {
+    marked: bool,
+    obj: Obj,
+}
+
+pub struct Heap {
+    objects: Vec<Option<HeapEntry>>, // Indexed by `GcRef`; freed slots are None
+    free_slots: Vec<usize>,          // Freed indices available for reuse
+    gray_stack: Vec<usize>,          // Marked objects whose references haven't been traced yet
+    bytes_allocated: usize,
+    next_gc: usize,
+    pub stress: bool, // Collect on every allocation; always on with the `gc_stress` feature
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: Vec::new(),
+            free_slots: Vec::new(),
+            gray_stack: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: INITIAL_GC_THRESHOLD,
+            stress: cfg!(feature = "gc_stress"),
+        }
+    }
+
+    // Moves `object` into the heap. Never collects by itself: callers check
+    // `should_collect` afterwards, once the new object can be marked as a root.
+    pub fn alloc<T: HeapObject>(&mut self, object: T) -> GcRef<T> {
+        let obj = object.into_obj();
+        self.bytes_allocated += obj.size();
+        let entry = HeapEntry { marked: false, obj };
+        let index = match self.free_slots.pop() {
+            Some(index) => {
+                self.objects[index] = Some(entry);
+                index
+            }
+            None => {
+                self.objects.push(Some(entry));
+                self.objects.len() - 1
+            }
+        };
+        GcRef::new(index)
+    }
+
+    // Under stress, every allocation triggers a full collection so that missing
+    // roots show up immediately as use-after-free panics.
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn get<T: HeapObject>(&self, reference: GcRef<T>) -> &T {
+        match &self.objects[reference.index] {
+            Some(entry) => T::from_obj(&entry.obj),
+            None => panic!("use of freed object {:?}", reference),
+        }
+    }
+
+    pub fn get_mut<T: HeapObject>(&mut self, reference: GcRef<T>) -> &mut T {
+        match &mut self.objects[reference.index] {
+            Some(entry) => T::from_obj_mut(&mut entry.obj),
+            None => panic!("use of freed object {:?}", reference),
+        }
+    }
+
+    /* ========== 标记 (Mark) ========== */
+
+    pub fn mark_value(&mut self, value: Value) {
+        match value.unpack() {
+            Unpacked::Nil | Unpacked::Bool(_) | Unpacked::Number(_) => {}
+            Unpacked::String(r) => self.mark_object(r),
+            Unpacked::Function(r) => self.mark_object(r),
+            Unpacked::Closure(r) => self.mark_object(r),
+            Unpacked::Class(r) => self.mark_object(r),
+            Unpacked::Instance(r) => self.mark_object(r),
+            Unpacked::BoundMethod(r) => self.mark_object(r),
+            Unpacked::Native(r) => self.mark_object(r),
+        }
+    }
+
+    pub fn mark_object<T>(&mut self, reference: GcRef<T>) {
+        self.mark_index(reference.index);
+    }
+
+    fn mark_index(&mut self, index: usize) {
+        if let Some(entry) = &mut self.objects[index]
+            && !entry.marked
+        {
+            entry.marked = true;
+            self.gray_stack.push(index);
+        }
+    }
+
+    // Marks the constants (and name) of a function that hasn't been moved into the heap yet.
+    pub fn mark_function_contents(&mut self, function: &Function) {
+        if let Some(name) = function.name {
+            self.mark_object(name);
+        }
+        for constant in &function.chunk.constants {
+            self.mark_value(*constant);
+        }
+    }
+
+    pub fn is_marked<T>(&self, reference: GcRef<T>) -> bool {
+        matches!(&self.objects[reference.index], Some(entry) if entry.marked)
+    }
+
+    /* ========== 回收 (Trace & Sweep) ========== */
+
+    // Marks everything reachable from the roots marked so far. Afterwards, any
+    // object that is still unmarked is garbage.
+    pub fn trace_references(&mut self) {
+        while let Some(index) = self.gray_stack.pop() {
+            self.blacken(index);
+        }
+    }
+
+    // Marks everything the object at `index` refers to.
+    fn blacken(&mut self, index: usize) {
+        // Take the object out while tracing so its children can be marked through `&mut self`.
+        // It is already marked, so a reference back to itself is correctly ignored.
+        let Some(entry) = self.objects[index].take() else {
+            return;
+        };
+        match &entry.obj {
+            Obj::String(_) => {}
+            Obj::Function(function) => self.mark_function_contents(function),
+            Obj::Closure(closure) => {
+                self.mark_object(closure.function);
+                for upvalue in &closure.upvalues {
+                    self.mark_object(*upvalue);
+                }
+            }
+            Obj::Upvalue(upvalue) => {
+                if let Upvalue::Closed(value) = upvalue {
+                    self.mark_value(*value);
+                }
+            }
+            Obj::Class(class) => {
+                self.mark_object(class.name);
+                for (name, method) in class.methods.iter() {
+                    self.mark_object(name);
+                    self.mark_object(method);
+                }
+            }
+            Obj::Instance(instance) => {
+                self.mark_object(instance.class);
+                for (name, value) in instance.fields.iter() {
+                    self.mark_object(name);
+                    self.mark_value(value);
+                }
+            }
+            Obj::BoundMethod(bound) => {
+                self.mark_value(bound.receiver);
+                self.mark_object(bound.method);
+            }
+            Obj::Native(native) => self.mark_object(native.name),
+        }
+        self.objects[index] = Some(entry);
+    }
+
+    // Frees every unmarked object and sets the threshold for the next collection.
+    pub fn sweep(&mut self) {
+        let mut live_bytes = 0;
+        for (index, slot) in self.objects.iter_mut().enumerate() {
+            match slot {
+                Some(entry) if entry.marked => {
+                    // Survivor: clear the mark for the next cycle.
+                    entry.marked = false;
+                    live_bytes += entry.obj.size();
+                }
+                Some(_) => {
+                    *slot = None;
+                    self.free_slots.push(index);
+                }
+                None => {}
+            }
+        }
+        self.bytes_allocated = live_bytes;
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(INITIAL_GC_THRESHOLD);
+    }
+}
+
+impl<T: HeapObject> std::ops::Index<GcRef<T>> for Heap {
+    type Output = T;
+    fn index(&self, reference: GcRef<T>) -> &T {
+        self.get(reference)
+    }
+}
+
+impl<T: HeapObject> std::ops::IndexMut<GcRef<T>> for Heap {
+    fn index_mut(&mut self, reference: GcRef<T>) -> &mut T {
+        self.get_mut(reference)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(heap: &mut Heap, length: usize) -> GcRef<LoxString> {
+        heap.alloc(LoxString::new("x".repeat(length), 0))
+    }
+
+    #[test]
+    fn collection_frees_unreachable_objects() {
+        let mut heap = Heap::new();
+        let kept = string(&mut heap, 10);
+        let kept_bytes = heap.bytes_allocated;
+        let garbage: Vec<_> = (0..100).map(|_| string(&mut heap, 1000)).collect();
+        assert!(heap.bytes_allocated > kept_bytes + 100 * 1000);
+
+        heap.mark_object(kept);
+        heap.trace_references();
+        heap.sweep();
+
+        assert_eq!(heap.bytes_allocated, kept_bytes);
+        assert_eq!(heap[kept].chars.len(), 10);
+        // Freed handles are reused by the next allocations.
+        let reused = string(&mut heap, 1);
+        assert!(garbage.contains(&reused));
+    }
+
+    #[test]
+    fn threshold_follows_the_live_heap() {
+        let mut heap = Heap::new();
+        heap.stress = false;
+        let big = string(&mut heap, INITIAL_GC_THRESHOLD / 2);
+        assert!(!heap.should_collect());
+        string(&mut heap, INITIAL_GC_THRESHOLD / 2);
+        assert!(heap.should_collect());
+
+        // The survivors set the next threshold...
+        heap.mark_object(big);
+        heap.trace_references();
+        heap.sweep();
+        assert!(!heap.should_collect());
+        assert_eq!(heap.next_gc, heap.bytes_allocated * GC_HEAP_GROW_FACTOR);
+
+        // ...but it never drops below the initial one.
+        heap.trace_references();
+        heap.sweep();
+        assert_eq!(heap.bytes_allocated, 0);
+        assert_eq!(heap.next_gc, INITIAL_GC_THRESHOLD);
+    }
+}
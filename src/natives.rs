@@ -0,0 +1,93 @@
+// natives.rs
+// The built-in functions every program starts with. Each one is registered
+// through `VM::define_native`, which checks arity and argument types before
+// the Rust code runs, so the bodies below can rely on their parameter types.
+use std::io::{self, BufRead};
+use std::time::Instant;
+
+use crate::object::ParamType;
+use crate::value::{Unpacked, Value};
+use crate::vm::VM;
+
+pub fn define_core_natives(vm: &mut VM) {
+    // clock(): seconds elapsed since the VM started, for timing benchmarks.
+    let start = Instant::now();
+    vm.define_native("clock", &[], move |_, _| {
+        Ok(Value::number(start.elapsed().as_secs_f64()))
+    });
+
+    // input(): the next line of standard input without its line ending, or nil at end of input.
+    vm.define_native("input", &[], |vm, _| {
+        let mut line = String::new();
+        match io::stdin().lock().read_line(&mut line) {
+            Ok(0) => Ok(Value::NIL),
+            Ok(_) => {
+                let trimmed = line.trim_end_matches(['\n', '\r']).len();
+                line.truncate(trimmed);
+                Ok(Value::from(vm.intern(line)))
+            }
+            Err(e) => Err(format!("Could not read input: {}.", e)),
+        }
+    });
+
+    // str(value): the value as `print` would show it.
+    vm.define_native("str", &[ParamType::Any], |vm, args| {
+        match args[0].unpack() {
+            Unpacked::String(_) => Ok(args[0]),
+            _ => {
+                let text = args[0].display(&vm.heap).to_string();
+                Ok(Value::from(vm.intern(text)))
+            }
+        }
+    });
+
+    // num(string): the number the string spells out, or nil if it isn't one.
+    vm.define_native("num", &[ParamType::String], |vm, args| {
+        let Unpacked::String(string) = args[0].unpack() else {
+            unreachable!("argument type checked by the VM")
+        };
+        Ok(match vm.heap[string].chars.trim().parse::<f64>() {
+            Ok(n) if n.is_finite() => Value::number(n),
+            _ => Value::NIL,
+        })
+    });
+
+    // len(string): the number of characters in the string.
+    vm.define_native("len", &[ParamType::String], |vm, args| {
+        let Unpacked::String(string) = args[0].unpack() else {
+            unreachable!("argument type checked by the VM")
+        };
+        Ok(Value::number(vm.heap[string].chars.chars().count() as f64))
+    });
+
+    // type(value): the name of the value's type, e.g. "number" or "instance".
+    vm.define_native("type", &[ParamType::Any], |vm, args| {
+        let name = args[0].type_name().to_string();
+        Ok(Value::from(vm.intern(name)))
+    });
+}
+
+// Exposes the script's command-line arguments (those after the script path).
+// Lox has no lists, so they are read one at a time:
+//   args(): how many arguments there are.
+//   arg(i): the i-th argument as a string, counting from 0, or nil if there is none.
+pub fn define_script_args(vm: &mut VM, args: Vec<String>) {
+    let count = args.len();
+    vm.define_native("args", &[], move |_, _| Ok(Value::number(count as f64)));
+
+    vm.define_native("arg", &[ParamType::Number], move |vm, params| {
+        let Unpacked::Number(index) = params[0].unpack() else {
+            unreachable!("argument type checked by the VM")
+        };
+        if index.fract() != 0.0 || index < 0.0 {
+            return Err(format!(
+                "Argument index must be a whole number, got {}.",
+                index
+            ));
+        }
+        match args.get(index as usize) {
+            Some(arg) => Ok(Value::from(vm.intern(arg.clone()))),
+            None => Ok(Value::NIL),
+        }
+    });
+}
@@ -0,0 +1,164 @@
+use std::rc::Rc;
+
+use crate::chunk::Chunk;
+use crate::memory::GcRef;
+use crate::table::Table;
+use crate::value::{Unpacked, Value};
+use crate::vm::VM;
+
+// An immutable Lox string living on the heap. Strings are interned, so there
+// is at most one LoxString with any given contents.
+pub struct LoxString {
+    pub chars: String,
+    pub hash: u32, // Cached, since the string is hashed on every table lookup
+}
+
+impl LoxString {
+    pub fn new(chars: String, hash: u32) -> Self {
+        LoxString { chars, hash }
+    }
+}
+
+// Where a closure finds one of its captured variables when it is created:
+// either a local slot of the enclosing function or one of the enclosing closure's upvalues.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpvalueRef {
+    pub is_local: bool,
+    pub index: usize,
+}
+
+// A compiled Lox function. Each function owns the chunk holding its bytecode.
+// The top-level script is compiled into an implicit function with no name.
+#[derive(Default)]
+pub struct Function {
+    pub arity: usize,
+    pub chunk: Chunk,
+    pub name: Option<GcRef<LoxString>>, // None for the top-level script
+    pub upvalues: Vec<UpvalueRef>,      // How to capture each upvalue when wrapped in a closure
+}
+
+impl Function {
+    pub fn new(name: Option<GcRef<LoxString>>) -> Self {
+        Function {
+            arity: 0,
+            chunk: Chunk::new(),
+            name,
+            upvalues: Vec::new(),
+        }
+    }
+}
+
+// A captured variable. While the variable is still alive on the stack the
+// upvalue points at its slot; once the slot goes away the value moves in here.
+pub enum Upvalue {
+    Open(usize), // Absolute index into the VM stack
+    Closed(Value),
+}
+
+// A function together with the variables it captured from enclosing scopes.
+// All Lox functions are wrapped in a closure at runtime.
+pub struct Closure {
+    pub function: GcRef<Function>,
+    pub upvalues: Vec<GcRef<Upvalue>>,
+}
+
+impl Closure {
+    pub fn new(function: GcRef<Function>, upvalues: Vec<GcRef<Upvalue>>) -> Self {
+        Closure { function, upvalues }
+    }
+}
+
+// A Lox class: a name plus the methods declared in its body.
+pub struct Class {
+    pub name: GcRef<LoxString>,
+    pub methods: Table<GcRef<Closure>>,
+    // Changes whenever `methods` does, see CacheEntry::Method. The VM hands out
+    // versions from one counter, so no two classes ever share one.
+    pub version: u64,
+}
+
+impl Class {
+    pub fn new(name: GcRef<LoxString>, version: u64) -> Self {
+        Class {
+            name,
+            methods: Table::new(),
+            version,
+        }
+    }
+}
+
+// An instance of a class. Fields are created on first assignment.
+pub struct Instance {
+    pub class: GcRef<Class>,
+    pub fields: Table<Value>,
+}
+
+impl Instance {
+    pub fn new(class: GcRef<Class>) -> Self {
+        Instance {
+            class,
+            fields: Table::new(),
+        }
+    }
+}
+
+// A method closure paired with the instance it was accessed on,
+// so `this` still refers to that instance when the method is called later.
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: GcRef<Closure>,
+}
+
+impl BoundMethod {
+    pub fn new(receiver: Value, method: GcRef<Closure>) -> Self {
+        BoundMethod { receiver, method }
+    }
+}
+
+// The Rust side of a native function. It receives the VM (to allocate results)
+// and the arguments, which have already been checked against the declared parameters.
+pub type NativeFn = Rc<dyn Fn(&mut VM, &[Value]) -> Result<Value, String>>;
+
+// What a native function accepts in one parameter position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamType {
+    Any,
+    Number,
+    String,
+}
+
+impl ParamType {
+    // The type name used in error messages, matching `Value::type_name`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ParamType::Any => "value",
+            ParamType::Number => "number",
+            ParamType::String => "string",
+        }
+    }
+
+    pub fn accepts(&self, value: &Value) -> bool {
+        match self {
+            ParamType::Any => true,
+            ParamType::Number => matches!(value.unpack(), Unpacked::Number(_)),
+            ParamType::String => matches!(value.unpack(), Unpacked::String(_)),
+        }
+    }
+}
+
+// A function implemented in Rust and exposed to Lox as a global.
+pub struct Native {
+    pub name: GcRef<LoxString>,
+    pub params: Vec<ParamType>, // Its length is the arity
+    pub function: NativeFn,
+}
+
+impl Native {
+    pub fn new(name: GcRef<LoxString>, params: Vec<ParamType>, function: NativeFn) -> Self {
+        Native {
+            name,
+            params,
+            function,
+        }
+    }
+}
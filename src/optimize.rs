@@ -0,0 +1,511 @@
+// optimize.rs
+// Optimizations over a function's finished chunk. The compiler runs them on
+// every function as it ends it, unless they are switched off with `-O0`.
+//
+// The chunk is decoded into a list of instructions, rewritten until nothing
+// changes, then encoded again:
+//
+// * Constant folding: arithmetic, comparisons, negation and `!` on literal
+//   operands are computed at compile time, e.g. `1 + 2 * 3` becomes one
+//   OP_CONSTANT. Division by zero and string concatenation are left to the VM.
+// * Dead code: instructions that no path from the start of the function
+//   reaches (such as anything after a `return`) are dropped.
+// * Jumps to the next instruction are dropped.
+//
+// Then common sequences are fused into superinstructions (see chunk.rs), which
+// the VM runs with a single dispatch:
+//
+//   GetLocal, Constant, Add        OP_GET_LOCAL_ADD_CONSTANT (and Subtract)
+//   Constant, Add                  OP_ADD_CONSTANT (and Subtract)
+//   Less, JumpIfFalse, Pop         OP_JUMP_IF_NOT_LESS (and Greater, Equal);
+//                                  the Pop at the jump target goes too
+//   Pop, Pop, ...                  OP_POP_N
+//
+// The superinstructions have no long forms, so constants past the first 256
+// are never fused.
+//
+// Constants that are no longer used are removed from the constant table
+// afterwards. No rewrite looks across a jump target, so every path still
+// sees the same values on the stack. A function whose optimized code would
+// need a jump longer than the compiler's 16 bits keeps its original code.
+use std::collections::{HashMap, HashSet};
+
+use crate::chunk::{Chunk, OpCode};
+use crate::value::{Unpacked, Value};
+
+// One decoded instruction. Jump operands hold the id of the target instruction
+// instead of a relative offset, so instructions can be removed freely.
+#[derive(Clone, Copy)]
+struct Instruction {
+    id: usize,      // Position in the original chunk, kept while the instruction is rewritten
+    op: OpCode,     // Always the short form; encode picks the long one if the index needs it
+    operand: usize, // Big-endian operand bytes, as the VM reads them
+    line: usize,
+}
+
+pub fn optimize(chunk: &mut Chunk) {
+    let mut instructions = decode(chunk);
+    // Folded results are added to a copy of the constants, shared the same way.
+    let mut constants = Chunk {
+        constants: chunk.constants.clone(),
+        constant_indexes: chunk.constant_indexes.clone(),
+        ..Chunk::new()
+    };
+    loop {
+        let changed = fold_constants(&mut instructions, &mut constants)
+            | remove_dead_code(&mut instructions)
+            | remove_jumps_to_next(&mut instructions);
+        if !changed {
+            break;
+        }
+    }
+    // Folding only knows the plain opcodes, so fusing comes last.
+    fuse_instructions(&mut instructions);
+    // Otherwise the function keeps the code the compiler wrote.
+    if let Some(optimized) = encode(&instructions, constants.constants) {
+        let caches = std::mem::take(&mut chunk.caches);
+        *chunk = optimized;
+        // Property accesses keep their cache operands, even if some were dropped.
+        chunk.caches = caches;
+    }
+}
+
+/* ========== 解码与编码 (Decode and encode) ========== */
+
+fn decode(chunk: &Chunk) -> Vec<Instruction> {
+    let mut instructions: Vec<Instruction> = chunk
+        .offsets()
+        .enumerate()
+        .map(|(id, offset)| {
+            let op = chunk
+                .op(offset)
+                .expect("compiler emitted an unknown opcode");
+            // A long form only widens the index, so the packed operand is the same.
+            let operand = (offset + 1..offset + op.size())
+                .fold(0, |acc, i| acc << 8 | chunk.code[i] as usize);
+            Instruction {
+                id,
+                op: op.short_form(),
+                operand,
+                line: chunk.line(offset),
+            }
+        })
+        .collect();
+
+    // Jumps now point at instructions rather than offsets.
+    let ids: HashMap<usize, usize> = chunk.offsets().enumerate().map(|(id, o)| (o, id)).collect();
+    for (offset, instruction) in chunk.offsets().zip(instructions.iter_mut()) {
+        if instruction.op.is_jump() {
+            instruction.operand = ids[&chunk.jump_target(offset)];
+        }
+    }
+    instructions
+}
+
+// Lays the instructions out again, dropping the constants nothing refers to.
+// Returns None if a jump no longer fits in its 16-bit offset: a folded result
+// can land past the first 256 constants and need a long form, so the code
+// doesn't always shrink.
+fn encode(instructions: &[Instruction], constants: Vec<Value>) -> Option<Chunk> {
+    let mut chunk = Chunk::new();
+
+    // Used constants keep their order (and so never move past the first 256).
+    let mut used = vec![false; constants.len()];
+    for instruction in instructions {
+        if let Some(index) = constant_operand(instruction) {
+            used[index] = true;
+        }
+    }
+    let mut renumbered = vec![None; constants.len()];
+    for (index, constant) in constants.into_iter().enumerate() {
+        if used[index] {
+            renumbered[index] = Some(chunk.add_constant(constant));
+        }
+    }
+
+    // Every instruction's new offset, to resolve jumps.
+    let mut offsets = HashMap::new();
+    let mut offset = 0;
+    for instruction in instructions {
+        offsets.insert(instruction.id, offset);
+        offset += size(instruction, &renumbered);
+    }
+
+    for instruction in instructions {
+        let line = instruction.line;
+        let start = chunk.code.len();
+        let mut operand = instruction.operand;
+        if let Some(index) = constant_operand(instruction) {
+            let index = renumbered[index].expect("constant marked as used");
+            operand = match instruction.op {
+                OpCode::SuperInvoke => index << 8 | (operand & 0xff),
+                OpCode::GetProperty | OpCode::SetProperty => index << 16 | (operand & 0xffff),
+                OpCode::Invoke => index << 24 | (operand & 0xff_ffff),
+                OpCode::GetLocalAddConstant | OpCode::GetLocalSubtractConstant => {
+                    (operand & !0xff) | index
+                }
+                _ => index,
+            };
+        }
+        let op = form(instruction, &renumbered);
+        if op.is_jump() {
+            // Relative to the end of the jump.
+            let target = offsets[&operand];
+            operand = match op {
+                OpCode::Loop => start + 3 - target,
+                _ => target - (start + 3),
+            };
+            if operand > u16::MAX as usize {
+                return None;
+            }
+        }
+        chunk.write(op as u8, line);
+        let bytes = (operand as u64).to_be_bytes();
+        for &byte in &bytes[9 - op.size()..] {
+            chunk.write(byte, line);
+        }
+    }
+    Some(chunk)
+}
+
+fn size(instruction: &Instruction, renumbered: &[Option<usize>]) -> usize {
+    form(instruction, renumbered).size()
+}
+
+// The opcode to write: the long form when the renumbered constant index
+// doesn't fit in one byte.
+fn form(instruction: &Instruction, renumbered: &[Option<usize>]) -> OpCode {
+    let index = constant_operand(instruction).and_then(|index| renumbered[index]);
+    match (index, instruction.op.long_form()) {
+        (Some(index), Some(long)) if index > u8::MAX as usize => long,
+        _ => instruction.op,
+    }
+}
+
+/* ========== 常量折叠 (Constant folding) ========== */
+
+fn fold_constants(instructions: &mut Vec<Instruction>, constants: &mut Chunk) -> bool {
+    let targets = jump_targets(instructions);
+    let mut changed = false;
+    let mut i = 0;
+    while i < instructions.len() {
+        // Try the longest pattern ending at `i` first: two literals and an operator.
+        let folded = (2..=3).rev().find_map(|length| {
+            let start = (i + 1).checked_sub(length)?;
+            let window = &instructions[start..=i];
+            // Jumping into the middle of the pattern would skip part of it.
+            if window[1..].iter().any(|w| targets.contains(&w.id)) {
+                return None;
+            }
+            let operands: Option<Vec<Value>> = window[..length - 1]
+                .iter()
+                .map(|w| literal(w, &constants.constants))
+                .collect();
+            Some((start, fold(window[length - 1].op, &operands?)?))
+        });
+        match folded {
+            Some((start, value)) => {
+                let line = instructions[i].line;
+                instructions[start] = push_value(instructions[start].id, value, line, constants);
+                instructions.drain(start + 1..=i);
+                changed = true;
+                // The result may be an operand of the instruction that follows it.
+                i = start + 1;
+            }
+            None => i += 1,
+        }
+    }
+    changed
+}
+
+// The value an instruction pushes, if it only ever pushes the same one.
+fn literal(instruction: &Instruction, constants: &[Value]) -> Option<Value> {
+    match instruction.op {
+        OpCode::Nil => Some(Value::NIL),
+        OpCode::True => Some(Value::bool(true)),
+        OpCode::False => Some(Value::bool(false)),
+        OpCode::Constant => Some(constants[instruction.operand]),
+        _ => None,
+    }
+}
+
+// What `op` computes from literal operands, or None when it's left to runtime.
+// Mirrors the VM's arithmetic helpers.
+fn fold(op: OpCode, operands: &[Value]) -> Option<Value> {
+    let unpacked: Vec<Unpacked> = operands.iter().map(|value| value.unpack()).collect();
+    match (op, unpacked.as_slice()) {
+        (OpCode::Negate, [Unpacked::Number(a)]) => Some(Value::number(-a)),
+        (OpCode::Not, [_]) => Some(Value::bool(operands[0].is_falsey())),
+        // Interned strings compare by identity, so `==` works on any literals.
+        (OpCode::Equal, [_, _]) => Some(Value::bool(operands[0] == operands[1])),
+        (OpCode::Add, [Unpacked::Number(a), Unpacked::Number(b)]) => Some(Value::number(a + b)),
+        (OpCode::Subtract, [Unpacked::Number(a), Unpacked::Number(b)]) => {
+            Some(Value::number(a - b))
+        }
+        (OpCode::Multiply, [Unpacked::Number(a), Unpacked::Number(b)]) => {
+            Some(Value::number(a * b))
+        }
+        // Dividing by zero is a runtime error, so it has to happen at runtime.
+        (OpCode::Divide, [Unpacked::Number(a), Unpacked::Number(b)]) if *b != 0.0 => {
+            Some(Value::number(a / b))
+        }
+        (OpCode::Greater, [Unpacked::Number(a), Unpacked::Number(b)]) => Some(Value::bool(a > b)),
+        (OpCode::Less, [Unpacked::Number(a), Unpacked::Number(b)]) => Some(Value::bool(a < b)),
+        _ => None,
+    }
+}
+
+// The instruction that pushes `value`, adding it to the constants if needed.
+fn push_value(id: usize, value: Value, line: usize, constants: &mut Chunk) -> Instruction {
+    let (op, operand) = match value.unpack() {
+        Unpacked::Nil => (OpCode::Nil, 0),
+        Unpacked::Bool(true) => (OpCode::True, 0),
+        Unpacked::Bool(false) => (OpCode::False, 0),
+        _ => (OpCode::Constant, constants.add_constant(value)),
+    };
+    Instruction {
+        id,
+        op,
+        operand,
+        line,
+    }
+}
+
+/* ========== 控制流 (Control flow) ========== */
+
+// Instructions no path from the start of the function reaches.
+fn remove_dead_code(instructions: &mut Vec<Instruction>) -> bool {
+    let positions = positions(instructions);
+    let mut reached = vec![false; instructions.len()];
+    let mut pending = vec![0];
+    while let Some(i) = pending.pop() {
+        if i >= instructions.len() || reached[i] {
+            continue;
+        }
+        reached[i] = true;
+        let instruction = &instructions[i];
+        match instruction.op {
+            OpCode::Return => {}
+            OpCode::Jump | OpCode::Loop => pending.push(positions[&instruction.operand]),
+            OpCode::JumpIfFalse => {
+                pending.push(positions[&instruction.operand]);
+                pending.push(i + 1);
+            }
+            _ => pending.push(i + 1),
+        }
+    }
+
+    let before = instructions.len();
+    let mut reached = reached.into_iter();
+    instructions.retain(|_| reached.next().unwrap_or(false));
+    instructions.len() != before
+}
+
+// `Jump` and `JumpIfFalse` to the very next instruction do nothing: the latter
+// leaves its condition on the stack either way.
+fn remove_jumps_to_next(instructions: &mut Vec<Instruction>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i + 1 < instructions.len() {
+        let instruction = instructions[i];
+        let next = instructions[i + 1].id;
+        if matches!(instruction.op, OpCode::Jump | OpCode::JumpIfFalse)
+            && instruction.operand == next
+        {
+            // Anything that jumped to the removed jump now lands where it would have gone.
+            for other in instructions.iter_mut() {
+                if other.op.is_jump() && other.operand == instruction.id {
+                    other.operand = next;
+                }
+            }
+            instructions.remove(i);
+            changed = true;
+        } else {
+            i += 1;
+        }
+    }
+    changed
+}
+
+/* ========== 超级指令 (Superinstructions) ========== */
+
+fn fuse_instructions(instructions: &mut Vec<Instruction>) {
+    let mut jumps = Jumps::new(instructions);
+    let mut fused = Vec::with_capacity(instructions.len());
+    let mut i = 0;
+    while i < instructions.len() {
+        // A Pop an earlier fused jump has taken over.
+        if jumps.removed.contains(&instructions[i].id) {
+            i += 1;
+            continue;
+        }
+        let (instruction, length) =
+            fuse_at(instructions, i, &mut jumps).unwrap_or((instructions[i], 1));
+        fused.push(instruction);
+        i += length;
+    }
+    *instructions = fused;
+}
+
+// What the fusing pass needs to know about jumps, worked out once for the
+// whole list and kept up to date as jumps are fused.
+struct Jumps {
+    targets: HashSet<usize>,        // Ids of the instructions some jump lands on
+    entries: HashMap<usize, usize>, // How many jumps land on each of them
+    positions: HashMap<usize, usize>, // Where each instruction is in the unfused list, by id
+    removed: HashSet<usize>,        // Pops after the current instruction that fusing dropped
+}
+
+impl Jumps {
+    fn new(instructions: &[Instruction]) -> Self {
+        let mut entries = HashMap::new();
+        for instruction in instructions.iter().filter(|i| i.op.is_jump()) {
+            *entries.entry(instruction.operand).or_insert(0) += 1;
+        }
+        Jumps {
+            targets: entries.keys().copied().collect(),
+            entries,
+            positions: positions(instructions),
+            removed: HashSet::new(),
+        }
+    }
+}
+
+// The instruction that replaces the sequence starting at `i`, and how many
+// instructions it replaces, if that sequence is one of the patterns.
+fn fuse_at(
+    instructions: &[Instruction],
+    i: usize,
+    jumps: &mut Jumps,
+) -> Option<(Instruction, usize)> {
+    let targets = &jumps.targets;
+    // The next `length` instructions, unless something jumps into the middle of them.
+    // Dropped Pops stay in `targets`, so no window reaches past one.
+    let window = |length: usize| {
+        instructions
+            .get(i..i + length)
+            .filter(|window| window[1..].iter().all(|w| !targets.contains(&w.id)))
+    };
+    let fused = |op, operand, line| Instruction {
+        id: instructions[i].id,
+        op,
+        operand,
+        line,
+    };
+
+    // The operator's line, since that's the part that can fail at runtime.
+    if let Some(&[local, constant, operator]) = window(3)
+        && local.op == OpCode::GetLocal
+        && constant.op == OpCode::Constant
+        && constant.operand <= u8::MAX as usize
+        && matches!(operator.op, OpCode::Add | OpCode::Subtract)
+    {
+        let op = match operator.op {
+            OpCode::Add => OpCode::GetLocalAddConstant,
+            _ => OpCode::GetLocalSubtractConstant,
+        };
+        let operand = local.operand << 8 | constant.operand;
+        Some((fused(op, operand, operator.line), 3))
+    } else if let Some(&[constant, operator]) = window(2)
+        && constant.op == OpCode::Constant
+        && constant.operand <= u8::MAX as usize
+        && matches!(operator.op, OpCode::Add | OpCode::Subtract)
+    {
+        let op = match operator.op {
+            OpCode::Add => OpCode::AddConstant,
+            _ => OpCode::SubtractConstant,
+        };
+        Some((fused(op, constant.operand, operator.line), 2))
+    } else if let Some(&[compare, jump, pop]) = window(3)
+        && matches!(compare.op, OpCode::Less | OpCode::Greater | OpCode::Equal)
+        && jump.op == OpCode::JumpIfFalse
+        && pop.op == OpCode::Pop
+        && let Some(target) = lone_pop_target(instructions, &jump, jumps)
+    {
+        let op = match compare.op {
+            OpCode::Less => OpCode::JumpIfNotLess,
+            OpCode::Greater => OpCode::JumpIfNotGreater,
+            _ => OpCode::JumpIfNotEqual,
+        };
+        // The fused jump pops the condition itself, on both paths, so it
+        // lands after the Pop at its target, which goes.
+        let landing = instructions[target + 1].id;
+        jumps.removed.insert(instructions[target].id);
+        jumps.targets.insert(landing);
+        *jumps.entries.entry(landing).or_insert(0) += 1;
+        Some((fused(op, landing, compare.line), 3))
+    } else {
+        let pops = instructions[i..]
+            .iter()
+            .take(u8::MAX as usize)
+            .enumerate()
+            .take_while(|&(k, w)| w.op == OpCode::Pop && (k == 0 || !targets.contains(&w.id)))
+            .count();
+        (pops >= 2).then(|| (fused(OpCode::PopN, pops, instructions[i].line), pops))
+    }
+}
+
+// Where `jump` (an OP_JUMP_IF_FALSE) lands, if that is an OP_POP only this
+// jump can reach: nothing else jumps there and the code before it never falls
+// through. Returns None when the Pop is also the last instruction.
+fn lone_pop_target(
+    instructions: &[Instruction],
+    jump: &Instruction,
+    jumps: &Jumps,
+) -> Option<usize> {
+    let target = jumps.positions[&jump.operand];
+    let only_entry = jumps.entries[&jump.operand] == 1;
+    let after_unconditional = target > 0
+        && matches!(
+            instructions[target - 1].op,
+            OpCode::Jump | OpCode::Loop | OpCode::Return
+        );
+    (instructions[target].op == OpCode::Pop
+        && only_entry
+        && after_unconditional
+        && target + 1 < instructions.len())
+    .then_some(target)
+}
+
+/* ========== 辅助函数 ========== */
+
+// Where each instruction currently is in the list, by id.
+fn positions(instructions: &[Instruction]) -> HashMap<usize, usize> {
+    instructions
+        .iter()
+        .enumerate()
+        .map(|(i, instruction)| (instruction.id, i))
+        .collect()
+}
+
+// The ids of all instructions some jump lands on.
+fn jump_targets(instructions: &[Instruction]) -> HashSet<usize> {
+    instructions
+        .iter()
+        .filter(|instruction| instruction.op.is_jump())
+        .map(|instruction| instruction.operand)
+        .collect()
+}
+
+// The constant table index an instruction refers to, if any.
+fn constant_operand(instruction: &Instruction) -> Option<usize> {
+    match instruction.op {
+        OpCode::Constant
+        | OpCode::DefineGlobal
+        | OpCode::GetGlobal
+        | OpCode::SetGlobal
+        | OpCode::Closure
+        | OpCode::Class
+        | OpCode::Method
+        | OpCode::GetSuper
+        | OpCode::AddConstant
+        | OpCode::SubtractConstant => Some(instruction.operand),
+        OpCode::SuperInvoke => Some(instruction.operand >> 8),
+        OpCode::GetProperty | OpCode::SetProperty => Some(instruction.operand >> 16),
+        OpCode::Invoke => Some(instruction.operand >> 24),
+        OpCode::GetLocalAddConstant | OpCode::GetLocalSubtractConstant => {
+            Some(instruction.operand & 0xff)
+        }
+        _ => None,
+    }
+}
@@ -5,12 +5,25 @@ pub struct Scanner {
     start: usize,
     current: usize,
     line: usize,
+    column: usize,       // Column of `current`, from 1
+    start_line: usize,   // Line of the token being scanned, which may span several lines
+    start_column: usize, // Column of the token being scanned
 }
+
+// A range of bytes in the source, end exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Token {
     pub kind: TokenType,
     pub lexeme: String,
-    pub line: usize,
+    pub line: usize,   // Line the token starts on, from 1
+    pub column: usize, // Column of its first character, from 1
+    pub span: Span,    // Where the token's text is in the source
 }
 impl Scanner {
     pub fn new(source: String) -> Self {
@@ -19,12 +32,17 @@ impl Scanner {
             start: 0,
             current: 0,
             line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
         }
     }
 
     pub fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
         }
@@ -73,8 +91,8 @@ impl Scanner {
                 }
             }
             '"' => self.string(),
-            _ if Self::is_alpha(c) => return self.identifier(),
-            _ => return self.error_token("Unexpected character.".to_string()), // Handle other cases as needed
+            _ if Self::is_alpha(c) => self.identifier(),
+            _ => self.error_token("Unexpected character.".to_string()), // Handle other cases as needed
         }
     }
     fn number(&mut self) -> Token {
@@ -93,13 +111,16 @@ impl Scanner {
     }
     fn string(&mut self) -> Token {
         while !self.is_at_end() && self.peek() != '"' {
-            if self.peek() == '\n' {
-                self.line += 1;
+            let c = self.advance();
+            if c == '\n' {
+                self.new_line();
             }
-            self.advance();
         }
         if self.is_at_end() {
-            return self.error_token("Unterminated string.".to_string());
+            // Point at the opening quote rather than at the rest of the file.
+            let mut token = self.error_token("Unterminated string.".to_string());
+            token.span.end = token.span.start + 1;
+            return token;
         }
         // 现在我们已经到达了结束的引号
         self.advance(); // 跳过结束的引号
@@ -111,6 +132,8 @@ impl Scanner {
         //这里能确保不会返回 None，因为 is_at_end 已经检查过了
         let c = self.source[self.current..].chars().next().unwrap();
         self.current += c.len_utf8();
+        // Columns count characters, not bytes, so they line up with what an editor shows.
+        self.column += 1;
         c
     }
     fn match_char(&mut self, expected: char) -> bool {
@@ -120,12 +143,19 @@ impl Scanner {
         let c = self.source[self.current..].chars().next().unwrap();
         if c == expected {
             self.current += c.len_utf8();
+            self.column += 1;
             true
         } else {
             false
         }
     }
 
+    // Called just after consuming a newline.
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.column = 1;
+    }
+
     fn is_at_end(&self) -> bool {
         self.current >= self.source.len()
     }
@@ -138,8 +168,8 @@ impl Scanner {
                     self.advance();
                 }
                 '\n' => {
-                    self.line += 1;
                     self.advance();
+                    self.new_line();
                 }
                 '/' => {
                     if self.peek_next() == '/' {
@@ -196,25 +226,133 @@ impl Scanner {
         c.is_ascii_alphabetic() || c == '_'
     }
     fn make_token(&self, kind: TokenType) -> Token {
-        Token {
-            kind,
-            lexeme: self.source[self.start..self.current].to_string(),
-            line: self.line,
-        }
+        let lexeme = self.source[self.start..self.current].to_string();
+        self.make_token_with_lexeme(kind, lexeme)
     }
 
     fn make_token_with_lexeme(&self, kind: TokenType, lexeme: String) -> Token {
         Token {
             kind,
-            lexeme: lexeme,
-            line: self.line,
+            lexeme,
+            line: self.start_line,
+            column: self.start_column,
+            span: Span {
+                start: self.start,
+                end: self.current,
+            },
         }
     }
+    // The lexeme of an error token is the error message; its span is the offending text.
     fn error_token(&self, message: String) -> Token {
-        Token {
-            kind: TokenType::Error,
-            lexeme: message,
-            line: self.line,
+        self.make_token_with_lexeme(TokenType::Error, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every token up to and including Eof.
+    fn scan(source: &str) -> Vec<Token> {
+        let mut scanner = Scanner::new(source.to_string());
+        let mut tokens = Vec::new();
+        loop {
+            let token = scanner.scan_token();
+            let done = token.kind == TokenType::Eof;
+            tokens.push(token);
+            if done {
+                return tokens;
+            }
         }
     }
+
+    // (line, column, start, end) of each token.
+    fn positions(source: &str) -> Vec<(usize, usize, usize, usize)> {
+        scan(source)
+            .iter()
+            .map(|t| (t.line, t.column, t.span.start, t.span.end))
+            .collect()
+    }
+
+    #[test]
+    fn columns_and_spans_on_one_line() {
+        assert_eq!(
+            positions("var ab = 12.5;"),
+            vec![
+                (1, 1, 0, 3),   // var
+                (1, 5, 4, 6),   // ab
+                (1, 8, 7, 8),   // =
+                (1, 10, 9, 13), // 12.5
+                (1, 14, 13, 14),
+                (1, 15, 14, 14), // Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn columns_restart_on_each_line() {
+        assert_eq!(
+            positions("a\n  b // comment\n\tc"),
+            vec![(1, 1, 0, 1), (2, 3, 4, 5), (3, 2, 18, 19), (3, 3, 19, 19)]
+        );
+    }
+
+    #[test]
+    fn columns_count_characters_and_spans_count_bytes() {
+        // "é" is two bytes, "日本" six.
+        let tokens = scan("\"é日本\" x");
+        assert_eq!(tokens[0].kind, TokenType::String);
+        assert_eq!(tokens[0].lexeme, "é日本");
+        assert_eq!(
+            (tokens[0].column, tokens[0].span),
+            (1, Span { start: 0, end: 10 })
+        );
+        assert_eq!(
+            (tokens[1].column, tokens[1].span),
+            (7, Span { start: 11, end: 12 })
+        );
+    }
+
+    #[test]
+    fn unexpected_character_spans_the_whole_character() {
+        let tokens = scan("a é");
+        assert_eq!(tokens[1].kind, TokenType::Error);
+        assert_eq!(tokens[1].lexeme, "Unexpected character.");
+        assert_eq!(
+            (tokens[1].column, tokens[1].span),
+            (3, Span { start: 2, end: 4 })
+        );
+        assert_eq!(
+            (tokens[2].column, tokens[2].span),
+            (4, Span { start: 4, end: 4 })
+        );
+    }
+
+    #[test]
+    fn multi_line_string_starts_where_its_quote_is() {
+        let tokens = scan("x = \"one\ntwo\" y");
+        assert_eq!(tokens[2].kind, TokenType::String);
+        assert_eq!((tokens[2].line, tokens[2].column), (1, 5));
+        assert_eq!(tokens[2].span, Span { start: 4, end: 13 });
+        // The token after it is on the string's last line.
+        assert_eq!((tokens[3].line, tokens[3].column), (2, 6));
+    }
+
+    #[test]
+    fn unterminated_string_points_at_its_quote() {
+        let tokens = scan("print \"never\nclosed");
+        assert_eq!(tokens[1].kind, TokenType::Error);
+        assert_eq!(tokens[1].lexeme, "Unterminated string.");
+        assert_eq!((tokens[1].line, tokens[1].column), (1, 7));
+        assert_eq!(tokens[1].span, Span { start: 6, end: 7 });
+    }
+
+    #[test]
+    fn eof_is_an_empty_span_after_the_last_character() {
+        let tokens = scan("x;\n");
+        let eof = tokens.last().unwrap();
+        assert_eq!(eof.kind, TokenType::Eof);
+        assert_eq!((eof.line, eof.column), (2, 1));
+        assert_eq!(eof.span, Span { start: 3, end: 3 });
+    }
 }
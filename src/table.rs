@@ -0,0 +1,318 @@
+// table.rs
+// A hash table keyed by interned strings, used for globals, instance fields,
+// class methods and the intern set itself. Because every key is interned, two
+// keys are the same string exactly when their handles are equal, so probing
+// never has to look at the characters (except in `find_string`, which is how
+// strings get interned in the first place).
+use crate::memory::{GcRef, Heap};
+use crate::object::LoxString;
+
+// Grow once the table is this full (tombstones included), keeping probe sequences short.
+const TABLE_MAX_LOAD: f64 = 0.75;
+
+#[derive(Clone)]
+enum Entry<V> {
+    Empty,
+    // A deleted entry. Probing continues past it, otherwise keys inserted after
+    // a collision would become unreachable once the earlier key is deleted.
+    Tombstone,
+    Occupied {
+        key: GcRef<LoxString>,
+        hash: u32, // The key's cached hash, so resizing never touches the heap
+        value: V,
+    },
+}
+
+// Open addressing with linear probing. The capacity is always a power of two
+// so the starting bucket is just the low bits of the hash.
+#[derive(Clone)]
+pub struct Table<V> {
+    entries: Vec<Entry<V>>,
+    count: usize, // Occupied entries plus tombstones
+}
+
+impl<V: Copy> Table<V> {
+    pub fn new() -> Self {
+        Table {
+            entries: Vec::new(),
+            count: 0,
+        }
+    }
+
+    // Approximate number of bytes owned by the table, used to pace collections.
+    pub fn size(&self) -> usize {
+        self.entries.len() * std::mem::size_of::<Entry<V>>()
+    }
+
+    pub fn get(&self, key: GcRef<LoxString>, hash: u32) -> Option<V> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        match self.entries[find_entry(&self.entries, key, hash)] {
+            Entry::Occupied { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    // The bucket `key` sits in, for inline caches. A bucket stays valid for the
+    // key until the table grows or the key is deleted, so users must check it
+    // with `get_at` / `get_at_mut`.
+    pub fn find_slot(&self, key: GcRef<LoxString>, hash: u32) -> Option<usize> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        let index = find_entry(&self.entries, key, hash);
+        matches!(self.entries[index], Entry::Occupied { .. }).then_some(index)
+    }
+
+    // The value in bucket `slot`, if that bucket holds `key`.
+    pub fn get_at(&self, slot: usize, key: GcRef<LoxString>) -> Option<V> {
+        match self.entries.get(slot) {
+            Some(Entry::Occupied {
+                key: found, value, ..
+            }) if *found == key => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn get_at_mut(&mut self, slot: usize, key: GcRef<LoxString>) -> Option<&mut V> {
+        match self.entries.get_mut(slot) {
+            Some(Entry::Occupied {
+                key: found, value, ..
+            }) if *found == key => Some(value),
+            _ => None,
+        }
+    }
+
+    // Inserts or overwrites `key`. Returns true if the key wasn't present before.
+    pub fn set(&mut self, key: GcRef<LoxString>, hash: u32, value: V) -> bool {
+        if (self.count + 1) as f64 > self.entries.len() as f64 * TABLE_MAX_LOAD {
+            let capacity = (self.entries.len() * 2).max(8);
+            self.adjust_capacity(capacity);
+        }
+        let index = find_entry(&self.entries, key, hash);
+        let entry = &mut self.entries[index];
+        let is_new_key = !matches!(entry, Entry::Occupied { .. });
+        // Reusing a tombstone doesn't change the count: it was already counted.
+        if let Entry::Empty = entry {
+            self.count += 1;
+        }
+        *entry = Entry::Occupied { key, hash, value };
+        is_new_key
+    }
+
+    // Removes `key`, leaving a tombstone. Returns true if the key was present.
+    pub fn delete(&mut self, key: GcRef<LoxString>, hash: u32) -> bool {
+        if self.entries.is_empty() {
+            return false;
+        }
+        let index = find_entry(&self.entries, key, hash);
+        if let Entry::Occupied { .. } = self.entries[index] {
+            self.entries[index] = Entry::Tombstone;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Copies every entry of this table into `to`, overwriting keys present in both.
+    pub fn add_all(&self, to: &mut Table<V>) {
+        for (key, hash, value) in self.entries() {
+            to.set(key, hash, value);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (GcRef<LoxString>, V)> + '_ {
+        self.entries().map(|(key, _, value)| (key, value))
+    }
+
+    // Deletes every entry whose key doesn't satisfy `keep`.
+    pub fn retain(&mut self, mut keep: impl FnMut(GcRef<LoxString>) -> bool) {
+        for entry in &mut self.entries {
+            if let Entry::Occupied { key, .. } = entry
+                && !keep(*key)
+            {
+                *entry = Entry::Tombstone;
+            }
+        }
+    }
+
+    // Looks a string up by its characters rather than by handle. This is the one
+    // place where keys are compared by content: it is how interning finds the
+    // existing copy of a string.
+    pub fn find_string(&self, chars: &str, hash: u32, heap: &Heap) -> Option<GcRef<LoxString>> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        let mask = self.entries.len() - 1;
+        let mut index = hash as usize & mask;
+        loop {
+            match &self.entries[index] {
+                Entry::Empty => return None,
+                Entry::Tombstone => {}
+                Entry::Occupied {
+                    key, hash: found, ..
+                } => {
+                    if *found == hash && heap[*key].chars == chars {
+                        return Some(*key);
+                    }
+                }
+            }
+            index = (index + 1) & mask;
+        }
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (GcRef<LoxString>, u32, V)> + '_ {
+        self.entries.iter().filter_map(|entry| match entry {
+            Entry::Occupied { key, hash, value } => Some((*key, *hash, *value)),
+            _ => None,
+        })
+    }
+
+    // Rehashes every live entry into a table of `capacity` buckets, dropping tombstones.
+    fn adjust_capacity(&mut self, capacity: usize) {
+        let old = std::mem::replace(&mut self.entries, vec![Entry::Empty; capacity]);
+        self.count = 0;
+        for entry in old {
+            if let Entry::Occupied { key, hash, .. } = entry {
+                let index = find_entry(&self.entries, key, hash);
+                self.entries[index] = entry;
+                self.count += 1;
+            }
+        }
+    }
+}
+
+// Returns the bucket holding `key`, or the bucket it should be inserted into:
+// the first tombstone passed on the way, if any, else the empty bucket that ended the probe.
+fn find_entry<V>(entries: &[Entry<V>], key: GcRef<LoxString>, hash: u32) -> usize {
+    let mask = entries.len() - 1;
+    let mut index = hash as usize & mask;
+    let mut tombstone = None;
+    loop {
+        match &entries[index] {
+            Entry::Empty => return tombstone.unwrap_or(index),
+            Entry::Tombstone => {
+                tombstone.get_or_insert(index);
+            }
+            Entry::Occupied { key: found, .. } => {
+                if *found == key {
+                    return index;
+                }
+            }
+        }
+        index = (index + 1) & mask;
+    }
+}
+
+// FNV-1a, the hash clox uses for strings.
+pub fn hash_string(chars: &str) -> u32 {
+    hash_bytes(chars.as_bytes())
+}
+
+// FNV-1a over raw bytes; also the checksum of .loxc files.
+pub fn hash_bytes(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for &byte in bytes {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Strings on a fresh heap, all with the same `hash` so they collide.
+    fn keys(heap: &mut Heap, names: &[&str], hash: u32) -> Vec<GcRef<LoxString>> {
+        names
+            .iter()
+            .map(|name| heap.alloc(LoxString::new(name.to_string(), hash)))
+            .collect()
+    }
+
+    #[test]
+    fn probing_continues_past_tombstones() {
+        let mut heap = Heap::new();
+        let k = keys(&mut heap, &["a", "b", "c"], 0);
+        let mut table = Table::new();
+        for (i, &key) in k.iter().enumerate() {
+            assert!(table.set(key, 0, i));
+        }
+        assert!(table.delete(k[1], 0));
+        assert!(!table.delete(k[1], 0));
+        assert_eq!(table.get(k[0], 0), Some(0));
+        assert_eq!(table.get(k[1], 0), None);
+        assert_eq!(table.get(k[2], 0), Some(2));
+    }
+
+    #[test]
+    fn tombstone_is_reused() {
+        let mut heap = Heap::new();
+        let k = keys(&mut heap, &["a", "b", "c", "d"], 0);
+        let mut table = Table::new();
+        for &key in &k[..3] {
+            table.set(key, 0, ());
+        }
+        table.delete(k[1], 0);
+        let (count, capacity) = (table.count, table.entries.len());
+        // The new key lands in the tombstone, so nothing is added to the count.
+        assert!(table.set(k[3], 0, ()));
+        assert_eq!((table.count, table.entries.len()), (count, capacity));
+        assert!(matches!(table.entries[1], Entry::Occupied { key, .. } if key == k[3]));
+        // Overwriting an existing key isn't a new key.
+        assert!(!table.set(k[2], 0, ()));
+    }
+
+    #[test]
+    fn resize_drops_tombstones() {
+        let mut heap = Heap::new();
+        let names: Vec<String> = (0..40).map(|i| i.to_string()).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let k = keys(&mut heap, &names, 3);
+        let mut table = Table::new();
+        for (i, &key) in k[..20].iter().enumerate() {
+            table.set(key, 3, i);
+        }
+        for &key in k[..20].iter().step_by(2) {
+            table.delete(key, 3);
+        }
+        // Grows while half of the counted entries are tombstones.
+        let capacity = table.entries.len();
+        for (i, &key) in k.iter().enumerate().skip(20) {
+            table.set(key, 3, i);
+        }
+        assert!(table.entries.len() > capacity);
+        for (i, &key) in k.iter().enumerate() {
+            let expected = (i >= 20 || i % 2 == 1).then_some(i);
+            assert_eq!(table.get(key, 3), expected, "key {i}");
+        }
+        assert!(
+            !table
+                .entries
+                .iter()
+                .any(|entry| matches!(entry, Entry::Tombstone))
+        );
+        assert_eq!(table.count, table.iter().count());
+    }
+
+    #[test]
+    fn find_string_after_deletes() {
+        let mut heap = Heap::new();
+        let k = keys(&mut heap, &["x", "y", "z"], 7);
+        let mut table = Table::new();
+        for &key in &k {
+            table.set(key, 7, ());
+        }
+        table.delete(k[0], 7);
+        table.delete(k[1], 7);
+        assert_eq!(table.find_string("x", 7, &heap), None);
+        assert_eq!(table.find_string("y", 7, &heap), None);
+        assert_eq!(table.find_string("z", 7, &heap), Some(k[2]));
+        assert_eq!(table.find_string("w", 7, &heap), None);
+        // Retained keys are found the same way.
+        table.retain(|key| key != k[2]);
+        assert_eq!(table.find_string("z", 7, &heap), None);
+    }
+}
@@ -0,0 +1,133 @@
+// trace.rs
+// Execution tracing. When a tracer is installed with `VM::set_tracer`, the VM
+// hands it every instruction just before executing it. Tracers decide what to
+// record and where to write it, so they can be combined (e.g. a filter in
+// front of a JSON writer) and swapped at runtime.
+use std::io::{self, Write};
+
+use crate::chunk::Chunk;
+use crate::debug;
+use crate::memory::Heap;
+use crate::value::Value;
+
+// Everything known about the instruction about to execute.
+pub struct TraceEvent<'a> {
+    pub function: Option<&'a str>, // None for the top-level script
+    pub offset: usize,             // Byte offset of the instruction in `chunk.code`
+    pub chunk: &'a Chunk,
+    pub stack: &'a [Value], // The whole VM stack, bottom first
+    pub heap: &'a Heap,
+}
+
+impl TraceEvent<'_> {
+    pub fn line(&self) -> usize {
+        self.chunk.line(self.offset)
+    }
+
+    // The function name as it appears in stack traces: "script" at the top level.
+    pub fn function_name(&self) -> &str {
+        self.function.unwrap_or("script")
+    }
+}
+
+pub trait Tracer {
+    fn trace(&mut self, event: &TraceEvent) -> io::Result<()>;
+}
+
+// Writes the stack, then the disassembled instruction, like clox's DEBUG_TRACE_EXECUTION.
+pub struct TextTracer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TextTracer<W> {
+    pub fn new(out: W) -> Self {
+        TextTracer { out }
+    }
+}
+
+impl<W: Write> Tracer for TextTracer<W> {
+    fn trace(&mut self, event: &TraceEvent) -> io::Result<()> {
+        write!(self.out, "          ")?;
+        for value in event.stack {
+            write!(self.out, "[ {} ]", value.display(event.heap))?;
+        }
+        writeln!(self.out)?;
+        let text = debug::dissemble_instruction(event.chunk, event.offset, event.heap);
+        writeln!(self.out, "{}", text)
+    }
+}
+
+// Writes one JSON object per line, e.g.
+// {"function":"fib","offset":3,"line":2,"instruction":"OP_LESS","stack":["<fn fib>","5","2"]}
+pub struct JsonTracer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> JsonTracer<W> {
+    pub fn new(out: W) -> Self {
+        JsonTracer { out }
+    }
+}
+
+impl<W: Write> Tracer for JsonTracer<W> {
+    fn trace(&mut self, event: &TraceEvent) -> io::Result<()> {
+        let instruction = debug::instruction_text(event.chunk, event.offset, event.heap);
+        let stack: Vec<String> = event
+            .stack
+            .iter()
+            .map(|value| json_string(&value.display(event.heap).to_string()))
+            .collect();
+        writeln!(
+            self.out,
+            "{{\"function\":{},\"offset\":{},\"line\":{},\"instruction\":{},\"stack\":[{}]}}",
+            json_string(event.function_name()),
+            event.offset,
+            event.line(),
+            json_string(&instruction),
+            stack.join(",")
+        )
+    }
+}
+
+// Forwards only the instructions executed directly inside the named functions
+// ("script" for top-level code).
+pub struct FunctionFilter {
+    functions: Vec<String>,
+    inner: Box<dyn Tracer>,
+}
+
+impl FunctionFilter {
+    pub fn new(functions: Vec<String>, inner: Box<dyn Tracer>) -> Self {
+        FunctionFilter { functions, inner }
+    }
+}
+
+impl Tracer for FunctionFilter {
+    fn trace(&mut self, event: &TraceEvent) -> io::Result<()> {
+        if self.functions.iter().any(|f| f == event.function_name()) {
+            self.inner.trace(event)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// Quotes and escapes `text` as a JSON string literal. Also used for
+// `--diagnostics=json`.
+pub fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -1,13 +1,253 @@
 use std::fmt;
-#[derive(Clone)]
-pub enum Value {
+
+use crate::memory::{GcRef, Heap};
+use crate::object::{BoundMethod, Class, Closure, Function, Instance, LoxString, Native};
+
+// A value taken apart into its type and contents, for matching on.
+// Objects live in the garbage-collected heap; a value only holds a handle to them,
+// so values are cheap to copy around the stack.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Unpacked {
+    Nil,
+    Bool(bool),
     Number(f64),
+    String(GcRef<LoxString>),
+    Function(GcRef<Function>),
+    Closure(GcRef<Closure>),
+    Class(GcRef<Class>),
+    Instance(GcRef<Instance>),
+    BoundMethod(GcRef<BoundMethod>),
+    Native(GcRef<Native>),
+}
+
+// A Lox value as stored on the stack, in constants, globals and fields.
+// Build one with `Value::NIL`, `Value::bool`, `Value::number` or `Value::from`
+// (for object handles), and look inside with `unpack`. The representation
+// depends on the `nan_boxing` feature; the API is the same either way.
+#[derive(Clone, Copy, PartialEq)]
+#[cfg(not(feature = "nan_boxing"))]
+pub struct Value(Unpacked);
+
+#[cfg(not(feature = "nan_boxing"))]
+impl Value {
+    pub const NIL: Value = Value(Unpacked::Nil);
+
+    pub fn bool(b: bool) -> Value {
+        Value(Unpacked::Bool(b))
+    }
+
+    pub fn number(n: f64) -> Value {
+        Value(Unpacked::Number(n))
+    }
+
+    pub fn unpack(self) -> Unpacked {
+        self.0
+    }
+}
+
+#[cfg(not(feature = "nan_boxing"))]
+impl From<Unpacked> for Value {
+    fn from(unpacked: Unpacked) -> Value {
+        Value(unpacked)
+    }
+}
+
+/* ========== NaN boxing ========== */
+
+// With `nan_boxing`, a value is a single u64. Numbers are stored as their f64
+// bits. Everything else hides in the payload of a quiet NaN that arithmetic
+// never produces (all of QNAN's bits set):
+//
+//   nil, false, true    QNAN | 1, QNAN | 2, QNAN | 3
+//   objects             SIGN | QNAN | kind << 47 | heap index
+//
+// Numbers that are NaN are stored as the one canonical NaN so that they can't
+// be mistaken for anything else.
+#[cfg(feature = "nan_boxing")]
+mod boxing {
+    pub const SIGN: u64 = 0x8000_0000_0000_0000;
+    pub const QNAN: u64 = 0x7ffc_0000_0000_0000;
+    pub const NIL: u64 = QNAN | 1;
+    pub const FALSE: u64 = QNAN | 2;
+    pub const TRUE: u64 = QNAN | 3;
+    pub const KIND_SHIFT: u32 = 47;
+    pub const INDEX_MASK: u64 = (1 << KIND_SHIFT) - 1;
+
+    // Object kinds, in the bits above the heap index.
+    pub const STRING: u64 = 0;
+    pub const FUNCTION: u64 = 1;
+    pub const CLOSURE: u64 = 2;
+    pub const CLASS: u64 = 3;
+    pub const INSTANCE: u64 = 4;
+    pub const BOUND_METHOD: u64 = 5;
+    pub const NATIVE: u64 = 6;
+}
+
+#[derive(Clone, Copy)]
+#[cfg(feature = "nan_boxing")]
+pub struct Value(u64);
+
+#[cfg(feature = "nan_boxing")]
+impl Value {
+    pub const NIL: Value = Value(boxing::NIL);
+
+    pub fn bool(b: bool) -> Value {
+        Value(if b { boxing::TRUE } else { boxing::FALSE })
+    }
+
+    pub fn number(n: f64) -> Value {
+        if n.is_nan() {
+            Value(f64::NAN.to_bits())
+        } else {
+            Value(n.to_bits())
+        }
+    }
+
+    fn object(kind: u64, index: usize) -> Value {
+        debug_assert!(index as u64 <= boxing::INDEX_MASK);
+        Value(boxing::SIGN | boxing::QNAN | kind << boxing::KIND_SHIFT | index as u64)
+    }
+
+    fn is_number(self) -> bool {
+        self.0 & boxing::QNAN != boxing::QNAN
+    }
+
+    pub fn unpack(self) -> Unpacked {
+        use boxing::*;
+        if self.is_number() {
+            return Unpacked::Number(f64::from_bits(self.0));
+        }
+        match self.0 {
+            NIL => return Unpacked::Nil,
+            FALSE => return Unpacked::Bool(false),
+            TRUE => return Unpacked::Bool(true),
+            _ => {}
+        }
+        let index = (self.0 & INDEX_MASK) as usize;
+        match (self.0 & !(SIGN | QNAN)) >> KIND_SHIFT {
+            STRING => Unpacked::String(GcRef::from_index(index)),
+            FUNCTION => Unpacked::Function(GcRef::from_index(index)),
+            CLOSURE => Unpacked::Closure(GcRef::from_index(index)),
+            CLASS => Unpacked::Class(GcRef::from_index(index)),
+            INSTANCE => Unpacked::Instance(GcRef::from_index(index)),
+            BOUND_METHOD => Unpacked::BoundMethod(GcRef::from_index(index)),
+            NATIVE => Unpacked::Native(GcRef::from_index(index)),
+            kind => unreachable!("bad object kind {} in value", kind),
+        }
+    }
+}
+
+#[cfg(feature = "nan_boxing")]
+impl From<Unpacked> for Value {
+    fn from(unpacked: Unpacked) -> Value {
+        use boxing::*;
+        match unpacked {
+            Unpacked::Nil => Value::NIL,
+            Unpacked::Bool(b) => Value::bool(b),
+            Unpacked::Number(n) => Value::number(n),
+            Unpacked::String(r) => Value::object(STRING, r.index()),
+            Unpacked::Function(r) => Value::object(FUNCTION, r.index()),
+            Unpacked::Closure(r) => Value::object(CLOSURE, r.index()),
+            Unpacked::Class(r) => Value::object(CLASS, r.index()),
+            Unpacked::Instance(r) => Value::object(INSTANCE, r.index()),
+            Unpacked::BoundMethod(r) => Value::object(BOUND_METHOD, r.index()),
+            Unpacked::Native(r) => Value::object(NATIVE, r.index()),
+        }
+    }
+}
+
+// Same as comparing the unpacked values: numbers compare as f64 (so NaN is not
+// equal to itself), everything else by its bits.
+#[cfg(feature = "nan_boxing")]
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        if self.is_number() && other.is_number() {
+            f64::from_bits(self.0) == f64::from_bits(other.0)
+        } else {
+            self.0 == other.0
+        }
+    }
+}
+
+/* ========== 通用接口 ========== */
+
+macro_rules! object_value {
+    ($variant:ident, $ty:ident) => {
+        impl From<GcRef<$ty>> for Value {
+            fn from(reference: GcRef<$ty>) -> Value {
+                Value::from(Unpacked::$variant(reference))
+            }
+        }
+    };
+}
+
+object_value!(String, LoxString);
+object_value!(Function, Function);
+object_value!(Closure, Closure);
+object_value!(Class, Class);
+object_value!(Instance, Instance);
+object_value!(BoundMethod, BoundMethod);
+object_value!(Native, Native);
+
+impl Value {
+    // Lox truthiness: only `nil` and `false` are falsey, everything else is truthy.
+    pub fn is_falsey(&self) -> bool {
+        matches!(self.unpack(), Unpacked::Nil | Unpacked::Bool(false))
+    }
+
+    // The name of the value's type, as reported by the `type()` built-in.
+    pub fn type_name(&self) -> &'static str {
+        match self.unpack() {
+            Unpacked::Nil => "nil",
+            Unpacked::Bool(_) => "bool",
+            Unpacked::Number(_) => "number",
+            Unpacked::String(_) => "string",
+            Unpacked::Function(_)
+            | Unpacked::Closure(_)
+            | Unpacked::BoundMethod(_)
+            | Unpacked::Native(_) => "function",
+            Unpacked::Class(_) => "class",
+            Unpacked::Instance(_) => "instance",
+        }
+    }
+
+    // Printing an object needs to look inside it, so formatting goes through the heap.
+    pub fn display<'a>(&self, heap: &'a Heap) -> ValueDisplay<'a> {
+        ValueDisplay { value: *self, heap }
+    }
+}
+
+pub struct ValueDisplay<'a> {
+    value: Value,
+    heap: &'a Heap,
+}
+
+impl ValueDisplay<'_> {
+    fn fmt_function(&self, f: &mut fmt::Formatter<'_>, function: GcRef<Function>) -> fmt::Result {
+        match self.heap[function].name {
+            Some(name) => write!(f, "<fn {}>", self.heap[name].chars),
+            None => write!(f, "<script>"),
+        }
+    }
 }
 
-impl fmt::Display for Value {
+impl fmt::Display for ValueDisplay<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Value::Number(n) => write!(f, "{}", n),
+        let heap = self.heap;
+        match self.value.unpack() {
+            Unpacked::Nil => write!(f, "nil"),
+            Unpacked::Bool(b) => write!(f, "{}", b),
+            Unpacked::Number(n) => write!(f, "{}", n),
+            Unpacked::String(s) => write!(f, "{}", heap[s].chars),
+            Unpacked::Function(function) => self.fmt_function(f, function),
+            Unpacked::Closure(closure) => self.fmt_function(f, heap[closure].function),
+            Unpacked::Class(class) => write!(f, "{}", heap[heap[class].name].chars),
+            Unpacked::Instance(instance) => {
+                let class = heap[instance].class;
+                write!(f, "{} instance", heap[heap[class].name].chars)
+            }
+            Unpacked::BoundMethod(bound) => self.fmt_function(f, heap[heap[bound].method].function),
+            Unpacked::Native(_) => write!(f, "<native fn>"),
         }
     }
 }
@@ -1,98 +1,258 @@
 // vm.rs
+use std::rc::Rc;
+
 use crate::{
-    chunk::{self, Chunk, OpCode}, // Need Chunk struct definition
+    chunk::{CacheEntry, Chunk, OpCode}, // Need Chunk struct definition
     compiler::Parser,
-    debug,
-    value::Value,
+    diagnostic::Diagnostic,
+    error::{InterpretError, RuntimeError, TraceFrame},
+    memory::{GcRef, Heap, HeapObject},
+    natives,
+    object::{
+        BoundMethod, Class, Closure, Function, Instance, LoxString, Native, ParamType, Upvalue,
+    },
+    table::{Table, hash_string},
+    trace::{TraceEvent, Tracer},
+    value::{Unpacked, Value},
 };
 
+// Maximum call depth before reporting a stack overflow.
+const FRAMES_MAX: usize = 64;
+
+// Counters about the VM's own behaviour, for `--stats`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VmStats {
+    pub cache_hits: u64,          // Property accesses answered by their inline cache
+    pub cache_misses: u64,        // Property accesses that had to look in the tables
+    pub megamorphic_lookups: u64, // Lookups at sites that gave up on caching
+}
+
+impl VmStats {
+    // Fraction of property accesses answered by an inline cache, 0 when there were none.
+    pub fn cache_hit_rate(&self) -> f64 {
+        let total = self.cache_hits + self.cache_misses + self.megamorphic_lookups;
+        if total == 0 {
+            0.0
+        } else {
+            self.cache_hits as f64 / total as f64
+        }
+    }
+}
+
+// An ongoing function call. Each frame has its own instruction pointer
+// and a window into the VM's value stack starting at `slot_base`.
+struct CallFrame {
+    closure: GcRef<Closure>,
+    function: GcRef<Function>, // The closure's function, cached to avoid a lookup per instruction
+    ip: usize,                 // Where the caller resumes; the running frame's ip is VM::ip
+    slot_base: usize,          // Stack index of slot zero (the callee itself)
+}
+
 pub struct VM {
-    ip: usize,
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
-    // Removed the chunk field from VM. VM will receive the chunk to run
-    // as a parameter to the run method.
-    // chunk: Chunk, // REMOVE THIS FIELD
+    globals: Table<Value>,              // Global variables, keyed by name
+    open_upvalues: Vec<GcRef<Upvalue>>, // Upvalues still pointing into the stack, by slot
+    pub heap: Heap,                     // Owns every object created by the compiler or at runtime
+    pub strings: Table<()>,             // Every live string, so equal strings share one object
+    init_string: GcRef<LoxString>,      // "init", looked up every time a class is called
+    tracer: Option<Box<dyn Tracer>>,    // Sees every instruction before it runs, if set
+    pub optimize: bool,                 // Whether the compiler runs the optimizer, see optimize.rs
+    class_versions: u64,                // Versions handed out to classes so far, see Class::version
+    stats: VmStats,
+    // The running frame's ip and function, kept out of `frames` because every
+    // byte read needs them. The frame's own `ip` is only saved on calls.
+    ip: usize,
+    function: Option<GcRef<Function>>,
 }
 
 impl VM {
     pub fn new() -> Self {
-        VM {
-            ip: 0,
+        let mut heap = Heap::new();
+        let mut strings = Table::new();
+        let init_string = heap.alloc(LoxString::new("init".to_string(), hash_string("init")));
+        strings.set(init_string, heap[init_string].hash, ());
+        let mut vm = VM {
+            frames: Vec::new(),
             stack: Vec::new(),
-            // Initialize chunk here if you add it back
-            // chunk: Chunk::new(), // Only if VM owns the chunk permanently
-        }
+            globals: Table::new(),
+            open_upvalues: Vec::new(),
+            heap,
+            strings,
+            init_string,
+            tracer: None,
+            optimize: true,
+            class_versions: 0,
+            stats: VmStats::default(),
+            ip: 0,
+            function: None,
+        };
+        natives::define_core_natives(&mut vm);
+        vm
     }
 
-    // 3. Modified interpret: Creates parser, compiles, gets chunk, and passes to run.
-    pub fn interpret(&mut self, source: String) -> Result<(), String> {
-        // Create the parser, it will create its own chunk
-        let mut parser = Parser::new(source);
+    // Installs a tracer that sees every instruction from now on. Tracing costs
+    // nothing but a branch per instruction while no tracer is set.
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
 
-        // Compile the source. parser.compile() returns the compiled chunk or an error.
-        let compiled_chunk = parser.compile()?; // Use ? to propagate parser errors
+    pub fn stats(&self) -> VmStats {
+        self.stats
+    }
 
-        // If compilation was successful, run the compiled chunk.
-        self.run(&compiled_chunk)?; // 4. Pass a reference to the compiled chunk to run
+    // Exposes a Rust function to Lox as the global `name`. Calls are checked
+    // against `params` (count and types) before `function` runs.
+    pub fn define_native<F>(&mut self, name: &str, params: &[ParamType], function: F)
+    where
+        F: Fn(&mut VM, &[Value]) -> Result<Value, String> + 'static,
+    {
+        // Keep the name on the stack while the native is allocated, so a collection can't free it.
+        let name = self.intern(name.to_string());
+        self.stack.push(Value::from(name));
+        let native = self.alloc(Native::new(name, params.to_vec(), Rc::new(function)));
+        self.globals
+            .set(name, self.heap[name].hash, Value::from(native));
+        self.stack.pop();
+    }
 
-        Ok(())
+    // Compiles `source` into the top-level script function without running it.
+    pub fn compile(&mut self, source: &str) -> Result<GcRef<Function>, Vec<Diagnostic>> {
+        // Create the parser, it will create its own chunk
+        let mut parser = Parser::new(source.to_string(), self);
+        parser.compile()
     }
 
-    // 4. Modified run: Takes a reference to the Chunk to execute.
-    fn run(&mut self, chunk: &Chunk) -> Result<(), String> {
-        // Added chunk parameter
-        // Reset instruction pointer and stack for the new execution
-        self.ip = 0;
+    // Creates parser, compiles the script into a function and runs it.
+    pub fn interpret(&mut self, source: &str) -> Result<(), InterpretError> {
+        let function = self.compile(source).map_err(InterpretError::Compile)?;
+        self.execute(function).map_err(InterpretError::Runtime)
+    }
+
+    // Runs a compiled top-level script function, e.g. one loaded from a .loxc file.
+    pub fn execute(&mut self, function: GcRef<Function>) -> Result<(), RuntimeError> {
+        // Reset the stack for the new execution and call the script like any other function.
         self.stack.clear();
+        self.frames.clear();
+        let closure = self.alloc(Closure::new(function, Vec::new()));
+        self.stack.push(Value::from(closure));
+        let result = self
+            .call(closure, 0)
+            .and_then(|()| self.run())
+            .map_err(|message| self.runtime_error(message));
+        if result.is_err() {
+            // Leave the VM in a clean state after an aborted run.
+            self.stack.clear();
+            self.frames.clear();
+            self.open_upvalues.clear();
+        }
+        result
+    }
 
+    fn run(&mut self) -> Result<(), String> {
         loop {
-            // 5. Access chunk data via the parameter
-            let instruction = self.read_byte(chunk); // Pass chunk to read_byte
+            let instruction = self.read_op();
 
-            // 仅在启用 `debug_print` 时打印调试信息
-            #[cfg(feature = "debug_print")]
-            {
-                self.debug_print_stack();
-                // 5. Access chunk data via the parameter
-                debug::dissemble_instruction(self.ip - 1, &instruction, chunk); // Pass chunk to dissemble_instruction
+            // 仅在设置了 tracer 时记录执行轨迹
+            if let Some(tracer) = &mut self.tracer {
+                let frame = self.frames.last().expect("no active call frame");
+                let function = &self.heap[frame.function];
+                let event = TraceEvent {
+                    function: function.name.map(|name| self.heap[name].chars.as_str()),
+                    offset: self.ip - 1,
+                    chunk: &function.chunk,
+                    stack: &self.stack,
+                    heap: &self.heap,
+                };
+                tracer
+                    .trace(&event)
+                    .map_err(|e| format!("Could not write trace: {}.", e))?;
             }
 
             match instruction {
                 OpCode::Return => {
-                    if self.stack.is_empty() {
-                        return Err("Stack is empty, cannot return.".to_string());
-                    } else {
-                        // The value is on top of the stack, pop and print it.
-                        // This assumes the script's result is left on the stack.
-                        // For functions, the return instruction would handle returning
-                        // the value and unwinding the call stack.
-                        let result = self.stack.pop().unwrap();
-                        println!("{}", result); // Use Debug or Display impl for Value
+                    let result = self.stack.pop().ok_or("Stack empty for Return.")?;
+                    let frame = self.frames.pop().expect("no call frame to return from");
+                    // Any of the callee's locals captured by closures must outlive its frame.
+                    self.close_upvalues(frame.slot_base);
+                    if self.frames.is_empty() {
+                        // Returning from the top-level script: pop the script function itself.
+                        self.stack.pop();
                         return Ok(()); // Script finished successfully
                     }
+                    // Discard the callee's slots and hand the result to the caller.
+                    self.stack.truncate(frame.slot_base);
+                    self.stack.push(result);
+                    self.ip = self.frame().ip;
+                    self.function = Some(self.frame().function);
+                }
+                OpCode::Print => {
+                    let value = self.stack.pop().ok_or("Stack empty for Print.")?;
+                    println!("{}", value.display(&self.heap));
+                }
+                OpCode::Pop => {
+                    self.stack.pop();
+                }
+                OpCode::GetLocal => {
+                    // Locals live directly in their stack slot, relative to the frame.
+                    let slot = self.read_byte();
+                    let value = self.stack[self.frame().slot_base + slot];
+                    self.stack.push(value);
+                }
+                OpCode::SetLocal => {
+                    // Assignment is an expression, so the value stays on the stack.
+                    let slot = self.read_byte();
+                    let value = *self.stack.last().ok_or("Stack empty for SetLocal.")?;
+                    let index = self.frame().slot_base + slot;
+                    self.stack[index] = value;
+                }
+                OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                    let name = self.read_name(instruction)?;
+                    let value = self.stack.pop().ok_or("Stack empty for DefineGlobal.")?;
+                    self.globals.set(name, self.heap[name].hash, value);
+                }
+                OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                    let name = self.read_name(instruction)?;
+                    match self.globals.get(name, self.heap[name].hash) {
+                        Some(value) => self.stack.push(value),
+                        None => {
+                            return Err(format!("Undefined variable '{}'.", self.heap[name].chars));
+                        }
+                    }
                 }
-                // 5. Access chunk data via the parameter
-                OpCode::Constant(index) => {
-                    // Ensure the index is within bounds
-                    if index >= chunk.constants.len() {
-                        return Err(format!("Invalid constant index: {}", index));
+                OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                    let name = self.read_name(instruction)?;
+                    // Assignment is an expression, so the value stays on the stack.
+                    let value = *self.stack.last().ok_or("Stack empty for SetGlobal.")?;
+                    let hash = self.heap[name].hash;
+                    if self.globals.set(name, hash, value) {
+                        // Assigning to an undeclared global is an error, not an implicit
+                        // declaration, so undo the insertion.
+                        self.globals.delete(name, hash);
+                        return Err(format!("Undefined variable '{}'.", self.heap[name].chars));
                     }
-                    let c = &chunk.constants[index];
-                    self.stack.push(c.clone()); // Clone constant value onto stack
+                }
+                OpCode::Constant => {
+                    let index = self.read_byte();
+                    self.push_constant(index)?;
+                }
+                OpCode::ConstantLong => {
+                    let index = self.read_u24();
+                    self.push_constant(index)?;
                 }
                 OpCode::Negate => {
                     // Use perform_unary_numeric_op instead for consistency (need to implement it)
                     // Or keep it here and handle the stack pop/push directly.
                     if let Some(value) = self.stack.pop() {
-                        match value {
-                            Value::Number(n) => {
-                                self.stack.push(Value::Number(-n));
+                        match value.unpack() {
+                            Unpacked::Number(n) => {
+                                self.stack.push(Value::number(-n));
                             }
                             // Handle other types or report runtime error
                             _ => {
                                 return Err(format!(
                                     "Operand must be a number for Negate. Got {}",
-                                    value
+                                    value.display(&self.heap)
                                 ));
                             }
                         }
@@ -100,66 +260,781 @@ impl VM {
                         return Err("Stack empty for Negate.".to_string());
                     }
                 }
-                // 5. Pass chunk to perform_binary_numeric_op
-                OpCode::Add => self.perform_binary_numeric_op(chunk, |a, b| a + b)?,
-                OpCode::Subtract => self.perform_binary_numeric_op(chunk, |a, b| a - b)?,
-                OpCode::Multiply => self.perform_binary_numeric_op(chunk, |a, b| a * b)?,
-                OpCode::Divide => self.perform_binary_numeric_op(chunk, |a, b| a / b)?,
-
-                // Add other opcodes (Equality, Comparison, Logical, Jumps, etc.)
-                // OpCode::Equal => self.perform_binary_equality_op()?, // Implement this
-                // OpCode::Greater => self.perform_binary_comparison_op(|a, b| a > b)?, // Implement this
-                // OpCode::Less => self.perform_binary_comparison_op(|a, b| a < b)?, // Implement this
-                // OpCode::Not => self.perform_unary_logical_op()?, // Implement this
-                // OpCode::JumpIfFalse(offset) => self.ip += offset, // Adjust based on how offsets are stored
-                // OpCode::Jump(offset) => self.ip += offset,
-                // OpCode::Pop => { self.stack.pop(); },
-                // OpCode::False => self.stack.push(Value::Bool(false)),
-                // OpCode::True => self.stack.push(Value::Bool(true)),
-                // OpCode::Nil => self.stack.push(Value::Nil),
-                // ... etc.
-                _ => return Err(format!("Unknown opcode: {:?}", instruction)), // Catch unimplemented opcodes
-            }
-        }
-    }
-
-    // 5. Modified read_byte: Takes a reference to Chunk.
-    fn read_byte(&mut self, chunk: &Chunk) -> OpCode {
-        // Added chunk parameter
-        if self.ip >= chunk.code.len() {
-            // This should not happen if the compiled code ends with OpCode::Return
-            // but as a safeguard:
-            panic!("Attempted to read past end of bytecode.");
-        }
-        let byte = &chunk.code[self.ip];
+                OpCode::Add => self.perform_add()?,
+                OpCode::Subtract => self.perform_binary_numeric_op(|a, b| a - b)?,
+                OpCode::Multiply => self.perform_binary_numeric_op(|a, b| a * b)?,
+                OpCode::Divide => self.perform_binary_numeric_op(|a, b| a / b)?,
+
+                OpCode::Equal => self.perform_binary_equality_op()?,
+                OpCode::Greater => self.perform_binary_comparison_op(|a, b| a > b)?,
+                OpCode::Less => self.perform_binary_comparison_op(|a, b| a < b)?,
+
+                // Jump offsets are relative to the instruction after the jump,
+                // which is where the frame's ip already points.
+                OpCode::Jump => {
+                    let offset = self.read_short();
+                    self.ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short();
+                    let condition = self.stack.last().ok_or("Stack empty for JumpIfFalse.")?;
+                    if condition.is_falsey() {
+                        self.ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_short();
+                    self.ip -= offset;
+                }
+                OpCode::Call => {
+                    // The callee sits just below its arguments on the stack.
+                    let arg_count = self.read_byte();
+                    let callee = self.stack[self.stack.len() - 1 - arg_count];
+                    self.call_value(callee, arg_count)?;
+                }
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let index = self.read_index(instruction);
+                    let constant = self.chunk().constants[index];
+                    let function = match constant.unpack() {
+                        Unpacked::Function(function) => function,
+                        _ => {
+                            return Err(format!(
+                                "Closure operand is not a function: {}",
+                                constant.display(&self.heap)
+                            ));
+                        }
+                    };
+                    let slot_base = self.frame().slot_base;
+                    let enclosing = self.frame().closure;
+                    let upvalues = self.heap[function]
+                        .upvalues
+                        .clone()
+                        .iter()
+                        .map(|upvalue| {
+                            if upvalue.is_local {
+                                self.capture_upvalue(slot_base + upvalue.index)
+                            } else {
+                                self.heap[enclosing].upvalues[upvalue.index]
+                            }
+                        })
+                        .collect();
+                    let closure = self.alloc(Closure::new(function, upvalues));
+                    self.stack.push(Value::from(closure));
+                }
+                OpCode::GetUpvalue => {
+                    let index = self.read_byte();
+                    let upvalue = self.heap[self.frame().closure].upvalues[index];
+                    let value = match self.heap[upvalue] {
+                        Upvalue::Open(slot) => self.stack[slot],
+                        Upvalue::Closed(value) => value,
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    // Assignment is an expression, so the value stays on the stack.
+                    let index = self.read_byte();
+                    let value = *self.stack.last().ok_or("Stack empty for SetUpvalue.")?;
+                    let upvalue = self.heap[self.frame().closure].upvalues[index];
+                    match &mut self.heap[upvalue] {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.stack.pop();
+                }
+                OpCode::Class | OpCode::ClassLong => {
+                    let name = self.read_name(instruction)?;
+                    let version = self.next_class_version();
+                    let class = self.alloc(Class::new(name, version));
+                    self.stack.push(Value::from(class));
+                }
+                OpCode::GetProperty | OpCode::GetPropertyLong => {
+                    let name = self.read_name(instruction)?;
+                    let cache = self.read_short();
+                    self.get_property(name, cache)?;
+                }
+                OpCode::SetProperty | OpCode::SetPropertyLong => {
+                    let name = self.read_name(instruction)?;
+                    let cache = self.read_short();
+                    self.set_property(name, cache)?;
+                }
+                OpCode::Method | OpCode::MethodLong => {
+                    let name = self.read_name(instruction)?;
+                    let method = match self.stack.pop().map(Value::unpack) {
+                        Some(Unpacked::Closure(closure)) => closure,
+                        _ => return Err("Method body is not a closure.".to_string()),
+                    };
+                    match self.stack.last().map(|value| value.unpack()) {
+                        Some(Unpacked::Class(class)) => {
+                            let hash = self.heap[name].hash;
+                            self.heap[class].methods.set(name, hash, method);
+                            self.heap[class].version = self.next_class_version();
+                        }
+                        _ => return Err("Method defined outside of a class.".to_string()),
+                    }
+                }
+                OpCode::Inherit => {
+                    let superclass = match self.stack[self.stack.len() - 2].unpack() {
+                        Unpacked::Class(superclass) => superclass,
+                        _ => {
+                            return Err("Superclass must be a class.".to_string());
+                        }
+                    };
+                    let subclass = match self.stack.pop().map(Value::unpack) {
+                        Some(Unpacked::Class(subclass)) => subclass,
+                        _ => return Err("Inherit target is not a class.".to_string()),
+                    };
+                    // Copy-down inheritance: the subclass starts with all of the superclass's
+                    // methods, and its own declarations (compiled afterwards) override them.
+                    let methods = self.heap[superclass].methods.clone();
+                    methods.add_all(&mut self.heap[subclass].methods);
+                    self.heap[subclass].version = self.next_class_version();
+                }
+                OpCode::GetSuper | OpCode::GetSuperLong => {
+                    let name = self.read_name(instruction)?;
+                    let superclass = self.pop_class()?;
+                    self.bind_method(superclass, name)?;
+                }
+                OpCode::Invoke | OpCode::InvokeLong => {
+                    let name = self.read_name(instruction)?;
+                    let arg_count = self.read_byte();
+                    let cache = self.read_short();
+                    self.invoke(name, arg_count, cache)?;
+                }
+                OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+                    let name = self.read_name(instruction)?;
+                    let arg_count = self.read_byte();
+                    let superclass = self.pop_class()?;
+                    self.invoke_from_class(superclass, name, arg_count)?;
+                }
+                OpCode::Nil => self.stack.push(Value::NIL),
+                OpCode::True => self.stack.push(Value::bool(true)),
+                OpCode::False => self.stack.push(Value::bool(false)),
+                OpCode::Not => self.perform_unary_logical_op()?,
+
+                // Superinstructions, see optimize.rs.
+                OpCode::PopN => {
+                    let count = self.read_byte();
+                    self.stack.truncate(self.stack.len() - count);
+                }
+                OpCode::AddConstant => {
+                    let index = self.read_byte();
+                    let value = self.stack.pop().ok_or("Stack empty for AddConstant.")?;
+                    self.binary_with_constant(OpCode::Add, value, index)?;
+                }
+                OpCode::SubtractConstant => {
+                    let index = self.read_byte();
+                    let value = self
+                        .stack
+                        .pop()
+                        .ok_or("Stack empty for SubtractConstant.")?;
+                    self.binary_with_constant(OpCode::Subtract, value, index)?;
+                }
+                // Stack slot, then constant index.
+                OpCode::GetLocalAddConstant => {
+                    let slot = self.read_byte();
+                    let index = self.read_byte();
+                    let value = self.stack[self.frame().slot_base + slot];
+                    self.binary_with_constant(OpCode::Add, value, index)?;
+                }
+                OpCode::GetLocalSubtractConstant => {
+                    let slot = self.read_byte();
+                    let index = self.read_byte();
+                    let value = self.stack[self.frame().slot_base + slot];
+                    self.binary_with_constant(OpCode::Subtract, value, index)?;
+                }
+                OpCode::JumpIfNotLess => {
+                    let offset = self.read_short();
+                    self.perform_binary_comparison_op(|a, b| a < b)?;
+                    self.pop_and_jump_if_false(offset);
+                }
+                OpCode::JumpIfNotGreater => {
+                    let offset = self.read_short();
+                    self.perform_binary_comparison_op(|a, b| a > b)?;
+                    self.pop_and_jump_if_false(offset);
+                }
+                OpCode::JumpIfNotEqual => {
+                    let offset = self.read_short();
+                    self.perform_binary_equality_op()?;
+                    self.pop_and_jump_if_false(offset);
+                }
+            }
+        }
+    }
+
+    /* ========== 函数调用 ========== */
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), String> {
+        match callee.unpack() {
+            Unpacked::Closure(closure) => self.call(closure, arg_count),
+            Unpacked::Class(class) => {
+                // Calling a class creates an instance, which replaces the class in slot zero
+                // so that `init` (if any) sees it as `this`.
+                let callee_slot = self.stack.len() - arg_count - 1;
+                let instance = self.alloc(Instance::new(class));
+                self.stack[callee_slot] = Value::from(instance);
+                let init = self.init_string;
+                let initializer = self.heap[class].methods.get(init, self.heap[init].hash);
+                match initializer {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    None if arg_count != 0 => {
+                        Err(format!("Expected 0 arguments but got {}.", arg_count))
+                    }
+                    None => Ok(()),
+                }
+            }
+            Unpacked::BoundMethod(bound) => {
+                let callee_slot = self.stack.len() - arg_count - 1;
+                let BoundMethod { receiver, method } = self.heap[bound];
+                self.stack[callee_slot] = receiver;
+                self.call(method, arg_count)
+            }
+            Unpacked::Native(native) => self.call_native(native, arg_count),
+            _ => Err("Can only call functions and classes.".to_string()),
+        }
+    }
+
+    // Pushes a new frame whose slot zero is the callee, followed by its arguments.
+    fn call(&mut self, closure: GcRef<Closure>, arg_count: usize) -> Result<(), String> {
+        let function = self.heap[closure].function;
+        let arity = self.heap[function].arity;
+        if arg_count != arity {
+            return Err(format!(
+                "Expected {} arguments but got {}.",
+                arity, arg_count
+            ));
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err("Stack overflow.".to_string());
+        }
+        if let Some(caller) = self.frames.last_mut() {
+            caller.ip = self.ip;
+        }
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slot_base: self.stack.len() - arg_count - 1,
+        });
+        self.ip = 0;
+        self.function = Some(function);
+        Ok(())
+    }
+
+    // Runs a native function to completion. There is no call frame: the callee and
+    // its arguments are simply replaced by the result.
+    fn call_native(&mut self, native: GcRef<Native>, arg_count: usize) -> Result<(), String> {
+        let params = &self.heap[native].params;
+        if arg_count != params.len() {
+            return Err(format!(
+                "Expected {} arguments but got {}.",
+                params.len(),
+                arg_count
+            ));
+        }
+        // The arguments stay on the stack during the call, so they remain reachable.
+        let args = self.stack[self.stack.len() - arg_count..].to_vec();
+        for (i, (param, arg)) in params.iter().zip(&args).enumerate() {
+            if !param.accepts(arg) {
+                let name = &self.heap[self.heap[native].name].chars;
+                return Err(format!(
+                    "Argument {} to '{}' must be a {}, got {}.",
+                    i + 1,
+                    name,
+                    param.name(),
+                    arg.type_name()
+                ));
+            }
+        }
+        let function = self.heap[native].function.clone();
+        let result = function(self, &args)?;
+        self.stack.truncate(self.stack.len() - arg_count - 1);
+        self.stack.push(result);
+        Ok(())
+    }
+
+    // Calls method `name` of `class` directly on the receiver already in the
+    // callee slot, without materializing a bound method.
+    fn invoke_from_class(
+        &mut self,
+        class: GcRef<Class>,
+        name: GcRef<LoxString>,
+        arg_count: usize,
+    ) -> Result<(), String> {
+        let method = self.find_method(class, name)?;
+        self.call(method, arg_count)
+    }
+
+    fn pop_class(&mut self) -> Result<GcRef<Class>, String> {
+        match self.stack.pop().map(Value::unpack) {
+            Some(Unpacked::Class(class)) => Ok(class),
+            _ => Err("Expected a class on the stack.".to_string()),
+        }
+    }
+
+    fn find_method(
+        &self,
+        class: GcRef<Class>,
+        name: GcRef<LoxString>,
+    ) -> Result<GcRef<Closure>, String> {
+        self.heap[class]
+            .methods
+            .get(name, self.heap[name].hash)
+            .ok_or_else(|| format!("Undefined property '{}'.", self.heap[name].chars))
+    }
+
+    // Replaces the instance on top of the stack with its method `name`, bound to it.
+    fn bind_method(&mut self, class: GcRef<Class>, name: GcRef<LoxString>) -> Result<(), String> {
+        let method = self.find_method(class, name)?;
+        self.bind_to_receiver(method)
+    }
+
+    fn bind_to_receiver(&mut self, method: GcRef<Closure>) -> Result<(), String> {
+        // The receiver stays on the stack, and so stays reachable, until the bound method exists.
+        let receiver = *self.stack.last().ok_or("Stack empty for bind_method.")?;
+        let bound = self.alloc(BoundMethod::new(receiver, method));
+        self.stack.pop();
+        self.stack.push(Value::from(bound));
+        Ok(())
+    }
+
+    /* ========== 内联缓存 (Inline caches) ========== */
+
+    // Every OP_GET_PROPERTY / OP_SET_PROPERTY owns a cache slot in its chunk that
+    // remembers what lookups found for each receiver class it has met, up to
+    // CACHE_CLASSES of them. A hit skips the hash probe; anything the cache
+    // can't vouch for takes the slow path.
+
+    // A version no class has had yet, for a new class or a changed method table.
+    fn next_class_version(&mut self) -> u64 {
+        self.class_versions += 1;
+        self.class_versions
+    }
+
+    fn get_property(&mut self, name: GcRef<LoxString>, cache_index: usize) -> Result<(), String> {
+        let instance = match self.stack.last().map(|value| value.unpack()) {
+            Some(Unpacked::Instance(instance)) => instance,
+            _ => {
+                return Err("Only instances have properties.".to_string());
+            }
+        };
+        let function = self.frame().function;
+        let class = self.heap[instance].class;
+        match self.heap[function].chunk.caches[cache_index].entry(class) {
+            CacheEntry::Field {
+                class: cached,
+                slot,
+            } if cached == class => {
+                // Instances of one class may still lay their fields out differently,
+                // so the bucket is only trusted if it holds this name.
+                if let Some(value) = self.heap[instance].fields.get_at(slot, name) {
+                    self.stats.cache_hits += 1;
+                    self.stack.pop(); // Instance.
+                    self.stack.push(value);
+                    return Ok(());
+                }
+            }
+            CacheEntry::Method {
+                class: cached,
+                method,
+                version,
+            } if cached == class && version == self.heap[class].version => {
+                // Fields shadow methods, so the method only applies while no field has its name.
+                let hash = self.heap[name].hash;
+                if self.heap[instance].fields.get(name, hash).is_none() {
+                    self.stats.cache_hits += 1;
+                    return self.bind_to_receiver(method);
+                }
+            }
+            CacheEntry::Megamorphic => {
+                self.stats.megamorphic_lookups += 1;
+                self.get_property_uncached(instance, name)?;
+                return Ok(());
+            }
+            _ => {}
+        }
+        self.stats.cache_misses += 1;
+        let entry = self.get_property_uncached(instance, name)?;
+        self.update_cache(function, cache_index, entry);
+        Ok(())
+    }
+
+    // Looks `name` up in `instance` (on top of the stack) the slow way and replaces
+    // the instance with the result. Returns the cache entry that would have hit.
+    fn get_property_uncached(
+        &mut self,
+        instance: GcRef<Instance>,
+        name: GcRef<LoxString>,
+    ) -> Result<CacheEntry, String> {
+        let class = self.heap[instance].class;
+        let fields = &self.heap[instance].fields;
+        // Fields shadow methods, so look there first.
+        if let Some(slot) = fields.find_slot(name, self.heap[name].hash) {
+            let value = fields.get_at(slot, name).expect("slot just found");
+            self.stack.pop(); // Instance.
+            self.stack.push(value);
+            return Ok(CacheEntry::Field { class, slot });
+        }
+        let method = self.find_method(class, name)?;
+        self.bind_to_receiver(method)?;
+        Ok(CacheEntry::Method {
+            class,
+            method,
+            version: self.heap[class].version,
+        })
+    }
+
+    fn set_property(&mut self, name: GcRef<LoxString>, cache_index: usize) -> Result<(), String> {
+        let value = self.stack.pop().ok_or("Stack empty for SetProperty.")?;
+        let instance = match self.stack.pop().map(Value::unpack) {
+            Some(Unpacked::Instance(instance)) => instance,
+            _ => {
+                return Err("Only instances have fields.".to_string());
+            }
+        };
+        let function = self.frame().function;
+        let class = self.heap[instance].class;
+        let entry = self.heap[function].chunk.caches[cache_index].entry(class);
+        if let CacheEntry::Field {
+            class: cached,
+            slot,
+        } = entry
+            && cached == class
+            && let Some(field) = self.heap[instance].fields.get_at_mut(slot, name)
+        {
+            self.stats.cache_hits += 1;
+            *field = value;
+        } else {
+            let hash = self.heap[name].hash;
+            let fields = &mut self.heap[instance].fields;
+            fields.set(name, hash, value);
+            if let CacheEntry::Megamorphic = entry {
+                self.stats.megamorphic_lookups += 1;
+            } else {
+                self.stats.cache_misses += 1;
+                let slot = fields.find_slot(name, hash).expect("field just set");
+                self.update_cache(function, cache_index, CacheEntry::Field { class, slot });
+            }
+        }
+        // Assignment is an expression, so the assigned value is its result.
+        self.stack.push(value);
+        Ok(())
+    }
+
+    // `instance.name(args)` without the bound method: the receiver is already in
+    // the callee slot, below the arguments, where the method expects `this`.
+    fn invoke(
+        &mut self,
+        name: GcRef<LoxString>,
+        arg_count: usize,
+        cache_index: usize,
+    ) -> Result<(), String> {
+        let receiver_slot = self.stack.len() - 1 - arg_count;
+        let instance = match self.stack[receiver_slot].unpack() {
+            Unpacked::Instance(instance) => instance,
+            _ => {
+                return Err("Only instances have methods.".to_string());
+            }
+        };
+        let function = self.frame().function;
+        let class = self.heap[instance].class;
+        match self.heap[function].chunk.caches[cache_index].entry(class) {
+            CacheEntry::Method {
+                class: cached,
+                method,
+                version,
+            } if cached == class && version == self.heap[class].version => {
+                let hash = self.heap[name].hash;
+                if self.heap[instance].fields.get(name, hash).is_none() {
+                    self.stats.cache_hits += 1;
+                    return self.call(method, arg_count);
+                }
+            }
+            CacheEntry::Field {
+                class: cached,
+                slot,
+            } if cached == class => {
+                if let Some(value) = self.heap[instance].fields.get_at(slot, name) {
+                    self.stats.cache_hits += 1;
+                    self.stack[receiver_slot] = value;
+                    return self.call_value(value, arg_count);
+                }
+            }
+            CacheEntry::Megamorphic => {
+                self.stats.megamorphic_lookups += 1;
+                self.invoke_uncached(instance, name, arg_count)?;
+                return Ok(());
+            }
+            _ => {}
+        }
+        self.stats.cache_misses += 1;
+        let entry = self.invoke_uncached(instance, name, arg_count)?;
+        self.update_cache(function, cache_index, entry);
+        Ok(())
+    }
+
+    fn invoke_uncached(
+        &mut self,
+        instance: GcRef<Instance>,
+        name: GcRef<LoxString>,
+        arg_count: usize,
+    ) -> Result<CacheEntry, String> {
+        let class = self.heap[instance].class;
+        let fields = &self.heap[instance].fields;
+        // A field holding a function shadows any method, and is called like
+        // any other value: it takes the receiver's place in the callee slot.
+        if let Some(slot) = fields.find_slot(name, self.heap[name].hash) {
+            let value = fields.get_at(slot, name).expect("slot just found");
+            let receiver_slot = self.stack.len() - 1 - arg_count;
+            self.stack[receiver_slot] = value;
+            self.call_value(value, arg_count)?;
+            return Ok(CacheEntry::Field { class, slot });
+        }
+        let method = self.find_method(class, name)?;
+        self.call(method, arg_count)?;
+        Ok(CacheEntry::Method {
+            class,
+            method,
+            version: self.heap[class].version,
+        })
+    }
+
+    // Stores what a slow lookup found, see `InlineCache::update`.
+    fn update_cache(&mut self, function: GcRef<Function>, cache_index: usize, entry: CacheEntry) {
+        self.heap[function].chunk.caches[cache_index].update(entry);
+    }
+
+    /* ========== 上值 (Upvalues) ========== */
+
+    // Returns the open upvalue for stack `slot`, creating it if this is the
+    // first closure to capture that variable, so closures share captured state.
+    fn capture_upvalue(&mut self, slot: usize) -> GcRef<Upvalue> {
+        let heap = &self.heap;
+        let position = self
+            .open_upvalues
+            .partition_point(|&upvalue| open_slot(heap, upvalue) < slot);
+        if let Some(&existing) = self.open_upvalues.get(position)
+            && open_slot(heap, existing) == slot
+        {
+            return existing;
+        }
+        let upvalue = self.alloc(Upvalue::Open(slot));
+        self.open_upvalues.insert(position, upvalue);
+        upvalue
+    }
+
+    // Closes every open upvalue pointing at `last` or any slot above it,
+    // copying the variable out of the stack before the slot is discarded.
+    fn close_upvalues(&mut self, last: usize) {
+        let heap = &self.heap;
+        let position = self
+            .open_upvalues
+            .partition_point(|&upvalue| open_slot(heap, upvalue) < last);
+        for upvalue in self.open_upvalues.drain(position..) {
+            let slot = open_slot(&self.heap, upvalue);
+            self.heap[upvalue] = Upvalue::Closed(self.stack[slot]);
+        }
+    }
+
+    /* ========== 字符串驻留 (Interning) ========== */
+
+    // Returns the one string object holding `chars`, allocating it on first use.
+    pub fn intern(&mut self, chars: String) -> GcRef<LoxString> {
+        let hash = hash_string(&chars);
+        if let Some(interned) = self.strings.find_string(&chars, hash, &self.heap) {
+            return interned;
+        }
+        let string = self.alloc(LoxString::new(chars, hash));
+        self.strings.set(string, hash, ());
+        string
+    }
+
+    /* ========== 垃圾回收 (GC) ========== */
+
+    // Keeps `value` alive across allocations made outside the interpreter loop,
+    // until the matching `pop_root`.
+    pub fn push_root(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    pub fn pop_root(&mut self) {
+        self.stack.pop();
+    }
+
+    // Moves `object` into the heap, collecting first if enough has been allocated.
+    // The new object is not yet reachable from any root, so it is marked explicitly.
+    pub fn alloc<T: HeapObject>(&mut self, object: T) -> GcRef<T> {
+        let reference = self.heap.alloc(object);
+        if self.heap.should_collect() {
+            self.heap.mark_object(reference);
+            self.collect_garbage();
+        }
+        reference
+    }
+
+    // Marks everything the VM can reach directly, then lets the heap trace and sweep.
+    // Anything else that must survive (e.g. functions still being compiled) has to
+    // be marked by the caller beforehand.
+    pub fn collect_garbage(&mut self) {
+        for &value in &self.stack {
+            self.heap.mark_value(value);
+        }
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
+        }
+        for (name, value) in self.globals.iter() {
+            self.heap.mark_object(name);
+            self.heap.mark_value(value);
+        }
+        for &upvalue in &self.open_upvalues {
+            self.heap.mark_object(upvalue);
+        }
+        self.heap.mark_object(self.init_string);
+        self.heap.trace_references();
+        // The intern table doesn't keep strings alive: drop the ones about to be freed.
+        let heap = &self.heap;
+        self.strings.retain(|string| heap.is_marked(string));
+        self.heap.sweep();
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("no active call frame")
+    }
+
+    // The chunk of the function currently executing.
+    fn chunk(&self) -> &Chunk {
+        &self.heap[self.function.expect("no active call frame")].chunk
+    }
+
+    // Fetches the next opcode. Each instruction's handler then reads its own
+    // operands, so only the bytes it needs are decoded.
+    fn read_op(&mut self) -> OpCode {
+        // Compiled and verified code only holds known, complete instructions
+        // and ends with OpCode::Return, so this can't fail.
+        OpCode::from_byte(self.read_byte() as u8).expect("unknown opcode")
+    }
+
+    // Fetches the next code byte, widened for use as an index or count.
+    fn read_byte(&mut self) -> usize {
+        let byte = self.chunk().code[self.ip];
         self.ip += 1;
-        // Assuming OpCode implements Copy or Clone
-        byte.clone() // Or *byte if OpCode is Copy
+        byte as usize
+    }
+
+    // Fetches a big-endian two-byte operand (jump offsets, inline cache indexes).
+    fn read_short(&mut self) -> usize {
+        let value = self.chunk().read_u16(self.ip);
+        self.ip += 2;
+        value
+    }
+
+    // Fetches a long form's three-byte constant index.
+    fn read_u24(&mut self) -> usize {
+        let value = self.chunk().read_u24(self.ip);
+        self.ip += 3;
+        value
     }
 
-    // 5. Modified debug_print_stack: Needs chunk for disassembling instruction.
-    #[cfg(feature = "debug_print")] // Keep the conditional compilation
-    fn debug_print_stack(&self) {
-        // Added chunk parameter
-        print!("          ");
-        for value in &self.stack {
-            print!("[ ");
-            // Ensure Value has a Display or Debug implementation for printing
-            print!("{}, ", value); // Use {:?} or {} depending on Value's traits
-            print!(" ]");
+    fn push_constant(&mut self, index: usize) -> Result<(), String> {
+        match self.chunk().constants.get(index) {
+            Some(&constant) => {
+                self.stack.push(constant);
+                Ok(())
+            }
+            None => Err(format!("Invalid constant index: {}", index)),
+        }
+    }
+
+    // Reads the constant index that starts `op`'s operands: one byte, or
+    // three in a long form.
+    fn read_index(&mut self, op: OpCode) -> usize {
+        if op.is_long() {
+            self.read_u24()
+        } else {
+            self.read_byte()
         }
-        println!();
-        // The call to debug::dissemble_instruction is now in `run` and passes the chunk
-        // debug::dissemble_instruction(self.ip - 1, &instruction, chunk); // REMOVE THIS LINE
     }
-    #[cfg(not(feature = "debug_print"))] // Add the corresponding non-debug version
-    fn debug_print_stack(&self, _chunk: &Chunk) {
-        // Do nothing when debug_print feature is not enabled
+
+    // Reads a name operand: the index of a string constant.
+    fn read_name(&mut self, op: OpCode) -> Result<GcRef<LoxString>, String> {
+        let index = self.read_index(op);
+        self.read_string(index)
     }
 
-    // 5. Modified perform_binary_numeric_op: Needs chunk for division by zero check (accessing self.chunk.code)
-    fn perform_binary_numeric_op<F>(&mut self, chunk: &Chunk, op: F) -> Result<(), String>
-    // Added chunk parameter
+    // Reads a constant that is known to hold a name (e.g. a global variable's).
+    fn read_string(&self, index: usize) -> Result<GcRef<LoxString>, String> {
+        match self
+            .chunk()
+            .constants
+            .get(index)
+            .map(|value| value.unpack())
+        {
+            Some(Unpacked::String(name)) => Ok(name),
+            _ => Err(format!("Invalid name constant index: {}", index)),
+        }
+    }
+
+    // Attaches the current call stack to an error message. Runs before any frame
+    // is unwound, so the trace shows where the error happened.
+    fn runtime_error(&self, message: String) -> RuntimeError {
+        let trace: Vec<TraceFrame> = self
+            .frames
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(depth, frame)| {
+                let function = &self.heap[frame.function];
+                let ip = if depth == 0 { self.ip } else { frame.ip };
+                TraceFrame {
+                    function: function.name.map(|name| self.heap[name].chars.clone()),
+                    // ip already points past the instruction being executed.
+                    line: function.chunk.line(ip.saturating_sub(1)),
+                }
+            })
+            .collect();
+        RuntimeError {
+            message,
+            line: trace.first().map_or(0, |frame| frame.line),
+            trace,
+        }
+    }
+
+    // `value + constant` or `value - constant` for the fused opcodes. Two numbers
+    // take a shortcut; anything else goes through the same helper as the unfused
+    // instructions, so errors and string concatenation behave the same.
+    fn binary_with_constant(
+        &mut self,
+        op: OpCode,
+        value: Value,
+        index: usize,
+    ) -> Result<(), String> {
+        let constant = self.chunk().constants[index];
+        if let (Unpacked::Number(a), Unpacked::Number(b)) = (value.unpack(), constant.unpack()) {
+            let result = if op == OpCode::Add { a + b } else { a - b };
+            self.stack.push(Value::number(result));
+            return Ok(());
+        }
+        self.stack.push(value);
+        self.stack.push(constant);
+        if op == OpCode::Add {
+            self.perform_add()
+        } else {
+            self.perform_binary_numeric_op(|a, b| a - b)
+        }
+    }
+
+    // The second half of the compare-and-jump opcodes: the comparison's result
+    // is on top of the stack.
+    fn pop_and_jump_if_false(&mut self, offset: usize) {
+        let condition = self.stack.pop().expect("comparison pushed its result");
+        if condition.is_falsey() {
+            self.ip += offset;
+        }
+    }
+
+    fn perform_binary_numeric_op<F>(&mut self, op: F) -> Result<(), String>
     where
         F: Fn(f64, f64) -> f64, // 假设操作是在两个 f64 上进行
     {
@@ -174,33 +1049,193 @@ impl VM {
         let b = self.stack.pop().unwrap();
         let a = self.stack.pop().unwrap();
 
-        match (a, b) {
-            (Value::Number(a_num), Value::Number(b_num)) => {
+        match (a.unpack(), b.unpack()) {
+            (Unpacked::Number(a_num), Unpacked::Number(b_num)) => {
                 // Check for division by zero, needs the current instruction type.
-                // self.ip points *after* the current instruction.
-                // So the current instruction is at chunk.code[self.ip - 1].
-                // 5. Access chunk data via the parameter
-                if let OpCode::Divide = chunk.code[self.ip - 1] {
-                    // Use chunk parameter
-                    if b_num == 0.0 {
-                        return Err("Division by zero.".to_string());
-                    }
+                // The frame's ip points *after* the current instruction.
+                if self.chunk().code[self.ip - 1] == OpCode::Divide as u8 && b_num == 0.0 {
+                    return Err("Division by zero.".to_string());
                 }
                 let result = op(a_num, b_num);
-                self.stack.push(Value::Number(result));
+                self.stack.push(Value::number(result));
+                Ok(())
+            }
+            // Handle type errors for non-numeric operands
+            _ => Err(format!(
+                "Operands must be numbers for binary operation. Got {} and {}",
+                a.display(&self.heap),
+                b.display(&self.heap)
+            )),
+        }
+    }
+
+    // `+` is overloaded: it adds two numbers or concatenates two strings.
+    fn perform_add(&mut self) -> Result<(), String> {
+        if self.stack.len() < 2 {
+            return Err("Not enough values on the stack for binary operation.".to_string());
+        }
+        let len = self.stack.len();
+        if let (Unpacked::String(a), Unpacked::String(b)) =
+            (self.stack[len - 2].unpack(), self.stack[len - 1].unpack())
+        {
+            let (a, b) = (&self.heap[a].chars, &self.heap[b].chars);
+            let mut result = String::with_capacity(a.len() + b.len());
+            result.push_str(a);
+            result.push_str(b);
+            // Both operands stay on the stack until the result is allocated.
+            let result = self.intern(result);
+            self.stack.truncate(len - 2);
+            self.stack.push(Value::from(result));
+            return Ok(());
+        }
+        let (a, b) = (self.stack[len - 2], self.stack[len - 1]);
+        match (a.unpack(), b.unpack()) {
+            (Unpacked::Number(_), Unpacked::Number(_)) => {
+                self.perform_binary_numeric_op(|a, b| a + b)
+            }
+            _ => Err(format!(
+                "Operands must be two numbers or two strings. Got {} and {}",
+                a.display(&self.heap),
+                b.display(&self.heap)
+            )),
+        }
+    }
+
+    fn perform_unary_logical_op(&mut self) -> Result<(), String> {
+        match self.stack.pop() {
+            Some(value) => {
+                self.stack.push(Value::bool(value.is_falsey()));
                 Ok(())
-            } // Handle type errors for non-numeric operands
-              // _ => {
-              //     return Err(format!(
-              //         "Operands must be numbers for binary operation. Got {} and {}",
-              //         a, b
-              //     ));
-              // }
+            }
+            None => Err("Stack empty for Not.".to_string()),
         }
     }
 
-    // Add similar methods for other operand types (equality, logical, etc.)
-    // fn perform_binary_equality_op(&mut self) -> Result<(), String> { ... }
-    // fn perform_binary_comparison_op<F>(&mut self, op: F) -> Result<(), String> { ... }
-    // fn perform_unary_logical_op(&mut self) -> Result<(), String> { ... }
+    // `==` works on any pair of values; mismatched types simply compare unequal.
+    fn perform_binary_equality_op(&mut self) -> Result<(), String> {
+        if self.stack.len() < 2 {
+            return Err("Not enough values on the stack for binary operation.".to_string());
+        }
+        let b = self.stack.pop().unwrap();
+        let a = self.stack.pop().unwrap();
+        // Strings are interned, so even they compare by identity.
+        self.stack.push(Value::bool(a == b));
+        Ok(())
+    }
+
+    fn perform_binary_comparison_op<F>(&mut self, op: F) -> Result<(), String>
+    where
+        F: Fn(f64, f64) -> bool,
+    {
+        if self.stack.len() < 2 {
+            return Err("Not enough values on the stack for binary operation.".to_string());
+        }
+        let b = self.stack.pop().unwrap();
+        let a = self.stack.pop().unwrap();
+        match (a.unpack(), b.unpack()) {
+            (Unpacked::Number(a_num), Unpacked::Number(b_num)) => {
+                self.stack.push(Value::bool(op(a_num, b_num)));
+                Ok(())
+            }
+            _ => Err(format!(
+                "Operands must be numbers for comparison. Got {} and {}",
+                a.display(&self.heap),
+                b.display(&self.heap)
+            )),
+        }
+    }
+}
+
+// Stack slot of an upvalue in `VM.open_upvalues`, which only holds open upvalues.
+fn open_slot(heap: &Heap, upvalue: GcRef<Upvalue>) -> usize {
+    match heap[upvalue] {
+        Upvalue::Open(slot) => slot,
+        Upvalue::Closed(_) => unreachable!("closed upvalue in the open list"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Allocates in every way a program can: closures and their upvalues, class
+    // methods and instances, string concatenation, and constants the compiler
+    // creates for nested functions.
+    const PROGRAM: &str = r#"
+var log = "";
+fun note(s) { log = log + s + ";"; }
+fun counter(prefix) {
+  var n = "";
+  fun next() { n = n + "i"; return prefix + n; }
+  return next;
+}
+class Node {
+  init(name, next) { this.name = name; this.next = next; }
+  names() {
+    if (this.next == nil) return this.name;
+    return this.name + "," + this.next.names();
+  }
+}
+var next = counter("c");
+var list = nil;
+for (var i = 0; i < 20; i = i + 1) {
+  list = Node(next(), list);
+  var garbage = "garbage" + "garbage";
+}
+note(list.names());
+var names = list.names;
+note(names());
+note(next());
+var total = 0;
+for (var i = 0; i < 100; i = i + 1) total = total + i;
+"#;
+
+    // Every global's name and value after running `source`.
+    fn globals_after(source: &str, stress: bool) -> Vec<(String, String)> {
+        let mut vm = VM::new();
+        vm.heap.stress = stress;
+        vm.interpret(source).expect("program runs");
+        let mut globals: Vec<_> = vm
+            .globals
+            .iter()
+            .map(|(name, value)| {
+                let value = value.display(&vm.heap).to_string();
+                (vm.heap[name].chars.clone(), value)
+            })
+            .collect();
+        globals.sort();
+        globals
+    }
+
+    #[test]
+    fn collecting_on_every_allocation_changes_nothing() {
+        let normal = globals_after(PROGRAM, false);
+        assert_eq!(globals_after(PROGRAM, true), normal);
+        let log = &normal.iter().find(|(name, _)| name == "log").unwrap().1;
+        assert!(log.starts_with("ci"), "{log}");
+        assert!(log.ends_with(&format!("c{};", "i".repeat(21))), "{log}");
+    }
+
+    #[test]
+    fn class_reusing_a_freed_handle_misses_the_cache() {
+        // Each iteration's class is garbage by the next one, so under stress its
+        // handle is reused, and `o.m()` must not call the old class's method.
+        let source = r#"
+var log = "";
+var tag = "";
+fun call(o) { return o.m(); }
+for (var i = 0; i < 10; i = i + 1) {
+  tag = tag + "i";
+  var mine = tag;
+  class C { m() { return mine; } }
+  log = log + call(C()) + ";";
+}
+"#;
+        let expected: String = (1..=10).map(|n| "i".repeat(n) + ";").collect();
+        for stress in [false, true] {
+            let globals = globals_after(source, stress);
+            let log = &globals.iter().find(|(name, _)| name == "log").unwrap().1;
+            assert_eq!(log, &expected, "stress: {stress}");
+        }
+    }
 }
@@ -0,0 +1,73 @@
+#![allow(dead_code)] // Each test file uses its own subset of these helpers.
+
+// Helpers shared by the integration tests: they run the built interpreter on
+// a script, the same way a user would.
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct Output {
+    pub stdout: String,
+    pub stderr: String,
+    pub code: i32,
+}
+
+// Runs `source` as a script, with `options` before the script path.
+pub fn run(options: &[&str], source: &str) -> Output {
+    let path = temp_path("lox");
+    std::fs::write(&path, source).expect("write script");
+    let mut args = options.to_vec();
+    args.push(path.to_str().expect("temp path is UTF-8"));
+    let output = clox(&args);
+    let _ = std::fs::remove_file(&path);
+    output
+}
+
+// Runs the interpreter with exactly `args`.
+pub fn clox(args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_clox-rs"))
+        .args(args)
+        .output()
+        .expect("run clox-rs");
+    Output {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        code: output.status.code().unwrap_or(-1),
+    }
+}
+
+// A fresh file name in the temp directory, unique within the test run.
+pub fn temp_path(extension: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    std::env::temp_dir().join(format!(
+        "clox-test-{}-{}.{extension}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+// Runs `source` with and without optimization, checks both agree, and returns
+// what it printed.
+pub fn run_optimized(source: &str) -> String {
+    let optimized = run(&[], source);
+    let unoptimized = run(&["-O0"], source);
+    assert_eq!(
+        optimized.stdout, unoptimized.stdout,
+        "stdout differs with -O0"
+    );
+    assert_eq!(
+        optimized.stderr, unoptimized.stderr,
+        "stderr differs with -O0"
+    );
+    assert_eq!(
+        optimized.code, unoptimized.code,
+        "exit code differs with -O0"
+    );
+    optimized.stdout
+}
+
+// `count` distinct number literals as expression statements, to push later
+// constants past the first 256.
+pub fn filler(count: usize) -> String {
+    (0..count).map(|i| format!("{i}.5;\n")).collect()
+}
@@ -0,0 +1,59 @@
+// `--diagnostics=json` reports compile errors as one JSON object per line, so
+// editors and other tools don't have to parse the rendered text.
+mod common;
+
+use common::{clox, run};
+
+#[test]
+fn json_lists_every_compile_error() {
+    let source = "var é = 1;\nprint \"héllo\" + ;\nvar x = \"open\n";
+    let output = run(&["--diagnostics=json"], source);
+    assert_eq!(output.code, 65);
+    assert_eq!(output.stdout, "");
+    assert_eq!(
+        output.stderr,
+        concat!(
+            r#"{"severity":"error","line":1,"column":5,"span":{"start":4,"end":6},"message":"Unexpected character.","note":null}"#,
+            "\n",
+            r#"{"severity":"error","line":2,"column":17,"span":{"start":29,"end":30},"message":"Expect expression.","note":null}"#,
+            "\n",
+            r#"{"severity":"error","line":3,"column":9,"span":{"start":39,"end":40},"message":"Unterminated string.","note":null}"#,
+            "\n",
+        )
+    );
+    // The spans are byte offsets into the script as written.
+    assert_eq!(&source[4..6], "é");
+    assert_eq!(&source[29..30], ";");
+}
+
+#[test]
+fn text_is_the_default() {
+    let source = "print 1 +;";
+    let default = run(&[], source);
+    assert_eq!(default.code, 65);
+    assert!(
+        default
+            .stderr
+            .starts_with("[line 1] Error at ';': Expect expression.\n")
+    );
+    assert_eq!(run(&["--diagnostics=text"], source).stderr, default.stderr);
+}
+
+#[test]
+fn runtime_errors_stay_text() {
+    let output = run(&["--diagnostics=json"], "print -\"a\";");
+    assert_eq!(output.code, 70);
+    assert!(output.stderr.starts_with("Operand must be a number"));
+    assert!(output.stderr.contains("[line 1] in script"));
+}
+
+#[test]
+fn unknown_format_is_a_usage_error() {
+    let output = clox(&["--diagnostics=xml"]);
+    assert_eq!(output.code, 64);
+    assert!(
+        output
+            .stderr
+            .starts_with("Unknown diagnostics format 'xml'.\n")
+    );
+}
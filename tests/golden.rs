@@ -0,0 +1,62 @@
+// Golden-file tests for the `--disassemble` listing: its format is documented
+// as stable in src/debug.rs, so any change to it (or to the code the compiler
+// and optimizer emit) shows up here as a diff against a checked-in file.
+//
+// After an intended change, regenerate the expected files with
+//   UPDATE_GOLDEN=1 cargo test --test golden
+// and review the diff before committing it.
+mod common;
+
+use std::path::PathBuf;
+
+use common::clox;
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name)
+}
+
+// Disassembles tests/golden/<script> with `options` and compares the listing
+// byte-for-byte with tests/golden/<expected>.
+fn check_listing(options: &[&str], script: &str, expected: &str) {
+    let script = golden_path(script);
+    let mut args = options.to_vec();
+    args.push("--disassemble");
+    args.push(script.to_str().expect("path is UTF-8"));
+    let output = clox(&args);
+    assert_eq!(output.code, 0, "disassembling failed: {}", output.stderr);
+
+    let expected = golden_path(expected);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&expected, &output.stdout).expect("write golden file");
+        return;
+    }
+    let golden = std::fs::read_to_string(&expected).expect("read golden file");
+    if output.stdout != golden {
+        // Point at the first differing line; the full listings follow.
+        let line = output
+            .stdout
+            .lines()
+            .zip(golden.lines())
+            .position(|(actual, expected)| actual != expected)
+            .unwrap_or_else(|| output.stdout.lines().count().min(golden.lines().count()));
+        panic!(
+            "listing differs from {} at line {}\n--- expected\n{}\n--- actual\n{}",
+            expected.display(),
+            line + 1,
+            golden,
+            output.stdout
+        );
+    }
+}
+
+#[test]
+fn optimized_listing_matches() {
+    check_listing(&[], "program.lox", "program.txt");
+}
+
+#[test]
+fn unoptimized_listing_matches() {
+    check_listing(&["-O0"], "program.lox", "program.O0.txt");
+}
@@ -0,0 +1,71 @@
+// Inline cache behaviour, as reported by `--stats`.
+mod common;
+
+use common::run;
+
+// The `--stats` line printed after the script's own output.
+fn stats(source: &str) -> String {
+    let output = run(&["--stats"], source);
+    assert_eq!(output.code, 0, "{}", output.stderr);
+    output
+        .stderr
+        .lines()
+        .find(|line| line.starts_with("inline caches:"))
+        .expect("no stats line")
+        .to_string()
+}
+
+// `count` classes that each set `x` in their initializer.
+fn classes(count: usize) -> String {
+    (0..count)
+        .map(|i| format!("class C{i} {{ init() {{ this.x = {i}; }} }}\nvar c{i} = C{i}();\n"))
+        .collect()
+}
+
+#[test]
+fn site_alternating_between_two_classes_keeps_caching() {
+    // Each site misses once per class, then every access hits, including
+    // the `this.x` sites in the initializers.
+    let source = format!(
+        "{}fun get(o) {{ return o.x; }}
+fun call(o) {{ return o.init(); }}
+for (var i = 0; i < 50; i = i + 1) {{ get(c0); get(c1); call(c0); call(c1); }}",
+        classes(2)
+    );
+    assert_eq!(
+        stats(&source),
+        "inline caches: 296 hits, 6 misses, 0 megamorphic (98.0% hit rate)"
+    );
+}
+
+#[test]
+fn site_meeting_too_many_classes_stops_caching() {
+    // The fifth class turns the `o.x` site megamorphic; the others stay cached.
+    let source = format!(
+        "{}fun get(o) {{ return o.x; }}
+for (var i = 0; i < 10; i = i + 1) {{ get(c0); get(c1); get(c2); get(c3); get(c4); }}",
+        classes(5)
+    );
+    assert_eq!(
+        stats(&source),
+        "inline caches: 0 hits, 10 misses, 45 megamorphic (0.0% hit rate)"
+    );
+}
+
+#[test]
+fn defining_an_unrelated_class_keeps_cached_methods() {
+    // A class declared inside the loop is a new class with new methods on every
+    // iteration, but `point.get()` and `point.get` only depend on Point's
+    // methods: after the first miss each, every access hits.
+    let source = "class Point { get() { return 1; } }
+var point = Point();
+for (var i = 0; i < 10; i = i + 1) {
+  class Other { get() { return 2; } }
+  point.get();
+  point.get;
+}";
+    assert_eq!(
+        stats(source),
+        "inline caches: 18 hits, 2 misses, 0 megamorphic (90.0% hit rate)"
+    );
+}
@@ -0,0 +1,92 @@
+// `receiver.name(args)` compiles to OP_INVOKE and `super.name(args)` to
+// OP_SUPER_INVOKE; they must behave exactly like a property access and a call.
+mod common;
+
+use common::{run, run_optimized};
+
+#[test]
+fn field_holding_a_closure_is_called() {
+    let source = r#"
+class Box {}
+fun greet(name) { return "hello " + name; }
+var box = Box();
+box.greet = greet;
+print box.greet("field");
+var prefix = "counted ";
+fun make() { var n = "i"; fun count() { n = n + "i"; return prefix + n; } return count; }
+box.count = make();
+print box.count();
+print box.count();
+"#;
+    assert_eq!(
+        run_optimized(source),
+        "hello field\ncounted ii\ncounted iii\n"
+    );
+}
+
+#[test]
+fn field_shadows_a_method() {
+    let source = r#"
+class Thing {
+  name() { return "method"; }
+}
+fun replacement() { return "field"; }
+var thing = Thing();
+print thing.name();
+thing.name = replacement;
+print thing.name();
+print Thing().name();
+"#;
+    assert_eq!(run_optimized(source), "method\nfield\nmethod\n");
+}
+
+#[test]
+fn methods_get_this_and_arguments() {
+    let source = r#"
+class Base {
+  init(x) { this.x = x; }
+  add(a, b) { return this.x + a + b; }
+  who() { return "base"; }
+}
+class Derived < Base {
+  init(x) { super.init(x * 10); }
+  add(a, b) { return super.add(a, b) + 1; }
+  who() { return "derived of " + super.who(); }
+}
+var d = Derived(2);
+print d.add(3, 4);
+print d.who();
+print Base(1).add(1, 1);
+"#;
+    assert_eq!(run_optimized(source), "28\nderived of base\n3\n");
+}
+
+#[test]
+fn invoking_on_a_non_instance() {
+    for receiver in ["\"text\"", "nil", "Object"] {
+        let source = format!("class Object {{}}\nvar x = {receiver};\nx.method();\n");
+        let output = run(&[], &source);
+        assert_eq!(output.code, 70);
+        assert!(
+            output
+                .stderr
+                .starts_with("Only instances have methods.\n[line 3]"),
+            "{}",
+            output.stderr
+        );
+    }
+    // A plain property access keeps its own message.
+    let output = run(&[], "var x = 1;\nprint x.field;\n");
+    assert!(output.stderr.starts_with("Only instances have properties."));
+}
+
+#[test]
+fn invoking_a_missing_method() {
+    let output = run(&[], "class A {}\nA().missing();\n");
+    assert_eq!(output.code, 70);
+    assert!(
+        output.stderr.starts_with("Undefined property 'missing'."),
+        "{}",
+        output.stderr
+    );
+}
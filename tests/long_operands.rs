@@ -0,0 +1,69 @@
+// Chunks with more than 256 constants: every instruction that names a constant
+// switches to its long form instead of failing to compile.
+mod common;
+
+use common::{filler, run, run_optimized};
+
+#[test]
+fn three_hundred_globals() {
+    let mut source: String = (0..300).map(|i| format!("var g{i} = {i};\n")).collect();
+    source.push_str("g299 = g299 + g1;\nprint g299;\nprint g0 + g150;\n");
+    assert_eq!(run_optimized(&source), "300\n150\n");
+}
+
+#[test]
+fn classes_and_closures_past_the_first_256_constants() {
+    // The filler comes first, so every name below needs a long operand.
+    let source = format!(
+        r#"
+class Base {{ hi(n) {{ return "base " + n; }} }}
+fun f() {{
+{0}
+  class Point < Base {{
+    init(x) {{ this.x = x; }}
+    sum() {{ return this.x + 1; }}
+    hi(n) {{
+{0}
+      return super.hi(n) + "!";
+    }}
+    bound() {{
+{0}
+      var method = super.hi;
+      return method("bound");
+    }}
+  }}
+  var p = Point(3);
+  p.y = 7;
+  print p.y;
+  print p.sum();
+  print p.hi("one");
+  print p.bound();
+  var sum = p.sum;
+  print sum();
+  fun inner() {{ return p.x * 2; }}
+  print inner();
+  global = "set";
+}}
+var global;
+f();
+print global;
+"#,
+        filler(300)
+    );
+    assert_eq!(
+        run_optimized(&source),
+        "7\n4\nbase one!\nbase bound\n4\n6\nset\n"
+    );
+}
+
+#[test]
+fn many_constants_compile_quickly() {
+    // Looking each constant up used to scan the whole table.
+    let mut source = filler(40000);
+    source.push_str("print 0.5 + 39999.5;\n");
+    let start = std::time::Instant::now();
+    let output = run(&["-O0"], &source);
+    let elapsed = start.elapsed();
+    assert_eq!(output.stdout, "40000\n");
+    assert!(elapsed.as_secs() < 10, "took {elapsed:?}");
+}
@@ -0,0 +1,82 @@
+// Precompiled .loxc files run exactly like the scripts they came from.
+mod common;
+
+use common::{clox, run, temp_path};
+
+const PROGRAM: &str = r#"
+class Shape {
+  init(name) { this.name = name; }
+  describe() { return this.name; }
+  area() { return 0; }
+}
+class Square < Shape {
+  init(side) { super.init("square"); this.side = side; }
+  area() { return this.side * this.side; }
+  describe() { return "a " + super.describe(); }
+}
+fun counter() {
+  var count = 0;
+  fun increment() { count = count + 1; return count; }
+  return increment;
+}
+var next = counter();
+next();
+print next();
+print Square(3).describe();
+print Square(3).area();
+var method = Square(2).describe;
+print method();
+fun adders() {
+  var total = "";
+  for (var i = 0; i < 3; i = i + 1) {
+    fun add(s) { total = total + s; }
+    add("x");
+  }
+  return total;
+}
+print adders();
+"#;
+
+#[test]
+fn compiled_file_runs_like_the_script() {
+    let expected = run(&[], PROGRAM);
+    assert_eq!(expected.stdout, "2\na square\n9\na square\nxxx\n");
+    for options in [&[][..], &["-O0"][..]] {
+        let script = temp_path("lox");
+        let compiled = temp_path("loxc");
+        std::fs::write(&script, PROGRAM).expect("write script");
+        let output = format!("--output={}", compiled.display());
+        let mut args = options.to_vec();
+        args.extend([output.as_str(), script.to_str().unwrap()]);
+        let compile = clox(&args);
+        assert_eq!(compile.code, 0, "{}", compile.stderr);
+
+        let loaded = clox(&[compiled.to_str().unwrap()]);
+        let _ = std::fs::remove_file(&script);
+        let _ = std::fs::remove_file(&compiled);
+        assert_eq!(loaded.stdout, expected.stdout, "{options:?}");
+        assert_eq!(loaded.code, 0, "{}", loaded.stderr);
+    }
+}
+
+#[test]
+fn corrupt_file_is_reported() {
+    let compiled = temp_path("loxc");
+    let script = temp_path("lox");
+    std::fs::write(&script, PROGRAM).expect("write script");
+    let output = format!("--output={}", compiled.display());
+    assert_eq!(clox(&[output.as_str(), script.to_str().unwrap()]).code, 0);
+    let mut bytes = std::fs::read(&compiled).expect("read compiled file");
+    *bytes.last_mut().unwrap() ^= 0xff;
+    std::fs::write(&compiled, bytes).expect("write compiled file");
+
+    let loaded = clox(&[compiled.to_str().unwrap()]);
+    let _ = std::fs::remove_file(&script);
+    let _ = std::fs::remove_file(&compiled);
+    assert_eq!(loaded.code, 65);
+    assert!(
+        loaded.stderr.contains("Checksum mismatch"),
+        "{}",
+        loaded.stderr
+    );
+}
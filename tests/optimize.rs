@@ -0,0 +1,148 @@
+// The optimizer must never change what a program does: every program here
+// runs with and without `-O0` and both runs have to agree.
+mod common;
+
+use common::{filler, run, run_optimized};
+
+// Folded results are added to the end of the constant table, so in a big
+// function they land past index 255, where superinstructions can't reach.
+#[test]
+fn folded_constants_past_the_first_256() {
+    let body = "var x = 10; print x + (1 + 2); print x - (100 * 2); print x + (7 * 3);";
+    let source = format!(
+        "fun f() {{\n{}{body}\n}}\nf();\n{}{body}\n",
+        filler(300),
+        filler(300)
+    );
+    assert_eq!(run_optimized(&source), "13\n-190\n31\n".repeat(2));
+}
+
+#[test]
+fn folding_across_types() {
+    // Only numbers fold arithmetically; `==`, `!` and comparisons mix types.
+    let source = r#"
+print 1 + 2 * 3 - 4 / 2;
+print -(3 - 5);
+print !nil;
+print !0;
+print !"";
+print 1 == 1.0;
+print nil == false;
+print "a" == "a";
+print "a" + "b" == "ab";
+print 1 == "1";
+print true == !false;
+print 2 < 3 == !(2 >= 3);
+print 0.1 + 0.2;
+"#;
+    assert_eq!(
+        run_optimized(source),
+        "5\n2\ntrue\nfalse\nfalse\ntrue\nfalse\ntrue\ntrue\nfalse\ntrue\ntrue\n0.30000000000000004\n"
+    );
+}
+
+#[test]
+fn mixed_types_are_left_to_the_vm() {
+    // These fail at runtime either way, with the same message and line.
+    for expression in [
+        "1 + nil",
+        "-\"a\"",
+        "true * 2",
+        "\"a\" < \"b\"",
+        "1 + \"a\"",
+    ] {
+        let output = run_optimized(&format!("print \"before\";\nprint {expression};\n"));
+        assert_eq!(output, "before\n", "{expression}");
+    }
+}
+
+#[test]
+fn division_by_zero_is_left_to_the_vm() {
+    let source = "print 1 / 2;\nprint 0 / 0 == 1;\n";
+    let output = run(&[], source);
+    assert_eq!(output.stdout, "0.5\n");
+    assert_eq!(output.code, 70);
+    assert!(
+        output.stderr.starts_with("Division by zero.\n[line 2]"),
+        "{}",
+        output.stderr
+    );
+    assert_eq!(run_optimized(source), "0.5\n");
+}
+
+#[test]
+fn unreachable_code_after_return() {
+    let source = r#"
+fun f(n) {
+  return n * 2;
+  print "unreachable";
+  n = 1 / 0;
+}
+fun g(n) {
+  if (n > 0) { return "positive"; print "unreachable"; } else { return "other"; }
+  print "unreachable";
+}
+fun h() {
+  while (true) { return "looped"; }
+  return "after";
+}
+print f(21);
+print g(1);
+print g(-1);
+print h();
+"#;
+    assert_eq!(run_optimized(source), "42\npositive\nother\nlooped\n");
+}
+
+#[test]
+fn jump_targets_after_code_is_removed() {
+    // Folded conditions and dropped code move every jump that crosses them.
+    let source = r#"
+var total = 0;
+for (var i = 0; i < 10; i = i + 1) {
+  if (1 + 1 == 2) total = total + i; else total = total - 1000;
+  if (false) { print "never"; print "never"; }
+  if (i < 5 and 2 * 3 > 5) total = total + (10 - 9);
+  while (nil) total = 0;
+}
+print total;
+fun classify(n) {
+  if (n < 0) return "negative";
+  if (n == 0) { return "zero"; print "dead"; print "dead"; }
+  if (n < 10 or 1 > 2) return "small";
+  return "large";
+}
+print classify(-1);
+print classify(0);
+print classify(5);
+print classify(50);
+"#;
+    assert_eq!(run_optimized(source), "50\nnegative\nzero\nsmall\nlarge\n");
+}
+
+#[test]
+fn code_that_grows_past_a_jump_offset() {
+    // `5` sits below index 256, but the folded `-5` lands past it and needs
+    // OP_CONSTANT_LONG, so the body of the `if` grows beyond a 16-bit jump.
+    let source = format!(
+        "fun f(c) {{\n  var x = 5;\n{}  if (c) {{\n{}  }}\n  print \"after\";\n}}\nf(false);\n",
+        filler(300),
+        "    x = -5;\n".repeat(10000)
+    );
+    assert_eq!(run_optimized(&source), "after\n");
+}
+
+#[test]
+fn large_functions_optimize_quickly() {
+    // Every `if` here fuses into OP_JUMP_IF_NOT_LESS. The fusing pass used to
+    // rescan the whole function for each instruction and took over a minute.
+    let ifs: String = (0..4000)
+        .map(|i| format!("  if (x < {i}) a = a + 1;\n"))
+        .collect();
+    let source = format!("fun f(x) {{\n  var a = 0;\n{ifs}  return a;\n}}\nprint f(1000);\n");
+    let start = std::time::Instant::now();
+    let output = run(&[], &source);
+    let elapsed = start.elapsed();
+    assert_eq!(output.stdout, "2999\n");
+    assert!(elapsed.as_secs() < 10, "took {elapsed:?}");
+}